@@ -0,0 +1,69 @@
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::Criterion;
+use rust_lisp_parser::optimize::compile_optimized;
+use rust_lisp_parser::optimize::Options;
+use rust_lisp_parser::simulator::compile;
+use rust_lisp_parser::simulator::Vm;
+
+/// Almost nothing but calls and variable lookups.
+const FIB: &str = "
+(fn fib n
+  (if (< n 2)
+    n
+    (+ (fib (- n 1)) (fib (- n 2)))))
+(fib 15)
+";
+
+/// A recursive function that spends its time in calls to tiny helpers.
+const HELPERS: &str = "
+(fn square n (* n n))
+(fn dec n (- n 1))
+(fn sum-squares n
+  (if (< n 1)
+    0
+    (+ (square n) (sum-squares (dec n)))))
+(sum-squares 200)
+";
+
+/// Calls to a function with a long body, most of which never runs, so
+/// the cost of a call shows up rather than that of running it.
+fn large_body() -> String {
+    let unused = (0..50)
+        .map(|i| format!("(+ {i} n)"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!("(fn big n (if (< n 1) 0 (if true (big (- n 1)) (list {unused})))) (big 200)")
+}
+
+fn calls(c: &mut Criterion) {
+    let bytecode = compile(FIB).unwrap();
+    let mut vm = Vm::new().unwrap();
+    c.bench_function("fib 15", |b| {
+        b.iter(|| vm.eval_bytecode(&bytecode).unwrap())
+    });
+
+    let bytecode = compile(&large_body()).unwrap();
+    c.bench_function("large body", |b| {
+        b.iter(|| vm.eval_bytecode(&bytecode).unwrap())
+    });
+}
+
+fn inlining(c: &mut Criterion) {
+    let mut group = c.benchmark_group("helpers");
+    for level in [0, 2] {
+        let bytecode = compile_optimized(HELPERS, &Options::new(level)).unwrap();
+        let mut vm = Vm::new().unwrap();
+        group.bench_function(format!("-O{level}"), |b| {
+            b.iter(|| vm.eval_bytecode(&bytecode).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = calls, inlining
+}
+criterion_main!(benches);
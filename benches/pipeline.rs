@@ -0,0 +1,86 @@
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::Criterion;
+use rust_lisp_parser::ast::Tree;
+use rust_lisp_parser::expander::Expander;
+use rust_lisp_parser::lexer::lex;
+use rust_lisp_parser::simulator::generate;
+use rust_lisp_parser::simulator::read;
+use rust_lisp_parser::simulator::Vm;
+
+/// One expression nested `depth` lists deep.
+fn deep_nesting(depth: usize) -> String {
+    let mut src = "(+ 1".repeat(depth);
+    src.push_str(" 1");
+    src.push_str(&")".repeat(depth));
+    src
+}
+
+/// `count` small top-level definitions, each followed by a call.
+fn many_forms(count: usize) -> String {
+    (0..count)
+        .map(|i| format!("(fn f{i} (x y) (list x (+ y {i})))\n(f{i} {i} 1)\n"))
+        .collect()
+}
+
+/// A loop, written as tail recursion, doing arithmetic on every iteration.
+fn arithmetic_loop(iterations: usize) -> String {
+    format!(
+        "(fn loop (n acc)
+           (if (< n 1)
+             acc
+             (loop (- n 1) (+ acc (* n n) (/ n 3) (- 0 n 7)))))
+         (loop {iterations} 0)"
+    )
+}
+
+/// Doubly recursive calls, with little else.
+fn recursive_calls(n: usize) -> String {
+    format!(
+        "(fn fib n
+           (if (< n 2)
+             n
+             (+ (fib (- n 1)) (fib (- n 2)))))
+         (fib {n})"
+    )
+}
+
+fn workloads() -> Vec<(&'static str, String)> {
+    vec![
+        ("deep nesting", deep_nesting(500)),
+        ("many forms", many_forms(500)),
+        ("arithmetic loop", arithmetic_loop(500)),
+        ("recursive calls", recursive_calls(12)),
+    ]
+}
+
+/// Each stage of the pipeline on its own, given the output of the one
+/// before it.
+fn stages(c: &mut Criterion) {
+    for (name, src) in workloads() {
+        let mut group = c.benchmark_group(name);
+
+        group.bench_function("lex", |b| b.iter(|| lex(&src).unwrap()));
+
+        let tokens = lex(&src).unwrap();
+        group.bench_function("parse", |b| {
+            b.iter(|| Tree::try_construct(&tokens).unwrap())
+        });
+
+        let forms = Expander::new().expand_all(read(&src).unwrap()).unwrap();
+        group.bench_function("generate", |b| b.iter(|| generate(&forms).unwrap()));
+
+        let bytecode = generate(&forms).unwrap();
+        let mut vm = Vm::new().unwrap();
+        group.bench_function("run", |b| b.iter(|| vm.eval_bytecode(&bytecode).unwrap()));
+
+        group.finish();
+    }
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = stages
+}
+criterion_main!(benches);
@@ -0,0 +1,377 @@
+//! A text form of bytecode, for reading what the compiler produced and for
+//! writing VM tests by hand.
+//!
+//! Each instruction is written as its offset, its name and its operands:
+//!
+//! ```text
+//! ; square.li:2
+//! 0000  DefineFunction square (n) ()
+//!           0000  Operation * r1 r0 r0
+//!           0001  Return r1
+//!       end
+//! 0001  Load r1 16
+//! 0002  Call r0 square r1 1
+//! ```
+//!
+//! Registers are written `r0`, `r1` and so on, and operands in the order
+//! of the [`Instruction`] variant. Constants are written like in source,
+//! with symbols and lists quoted, except that lists holding `nil` or
+//! booleans are built with `list`, as in `(list 1 nil '(a))`. A function's
+//! body follows its `DefineFunction`, up to `end`, and its captures are a
+//! list of `(local rn)` and `(upvalue n)`. Upvalue indices, there and in
+//! `LoadUpvalue`, count the captures of the enclosing function, so top-level
+//! code has none. Comments, such as the source locations and jump targets
+//! added by [`disassemble`], are ignored by [`assemble`], as is the layout.
+//! Offsets are optional when assembling, but must be right if they are
+//! given.
+
+use crate::intern::SymbolId;
+use crate::resolver::Capture;
+use crate::simulator::read;
+use crate::simulator::Function;
+use crate::simulator::Instruction;
+use crate::simulator::Op;
+use crate::simulator::Register;
+use crate::simulator::Value;
+use crate::Error;
+
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::rc::Rc;
+
+use anyhow::Result;
+
+/// Writes `bytecode` as text, with each of `annotations` as a comment above
+/// the instruction at its offset.
+pub fn disassemble(bytecode: &[Instruction], annotations: &[(usize, String)]) -> String {
+    let mut out = String::new();
+    write_code(&mut out, bytecode, annotations, 0);
+    out
+}
+
+fn write_code(
+    out: &mut String,
+    bytecode: &[Instruction],
+    annotations: &[(usize, String)],
+    indent: usize,
+) {
+    let pad = " ".repeat(indent);
+    for (offset, instruction) in bytecode.iter().enumerate() {
+        for (_, annotation) in annotations.iter().filter(|(at, _)| *at == offset) {
+            let _ = writeln!(out, "{pad}; {annotation}");
+        }
+
+        let mut line = format!("{pad}{offset:04}  ");
+        match instruction {
+            Instruction::Load(dst, value) => {
+                let _ = write!(line, "Load r{dst} {}", literal(value));
+            }
+            Instruction::Move(dst, src) => {
+                let _ = write!(line, "Move r{dst} r{src}");
+            }
+            Instruction::Operation(op, dst, a, b) => {
+                let _ = write!(line, "Operation {} r{dst} r{a} r{b}", op_name(op));
+            }
+            Instruction::Call(dst, name, start, n) => {
+                let _ = write!(line, "Call r{dst} {name} r{start} {n}");
+            }
+            Instruction::CallValue(dst, callee, start, n) => {
+                let _ = write!(line, "CallValue r{dst} r{callee} r{start} {n}");
+            }
+            Instruction::LoadUpvalue(dst, index) => {
+                let _ = write!(line, "LoadUpvalue r{dst} {index}");
+            }
+            Instruction::LoadGlobal(dst, name) => {
+                let _ = write!(line, "LoadGlobal r{dst} {name}");
+            }
+            Instruction::DefineFunction(function) => {
+                write_function(out, line, function, indent);
+                continue;
+            }
+            Instruction::Jump(n) => {
+                let _ = write!(line, "Jump {n}  ; -> {:04}", offset + 1 + n);
+            }
+            Instruction::JumpUnless(condition, n) => {
+                let target = offset + 1 + n;
+                let _ = write!(line, "JumpUnless r{condition} {n}  ; -> {target:04}");
+            }
+            Instruction::MakeList(dst, start, n) => {
+                let _ = write!(line, "MakeList r{dst} r{start} {n}");
+            }
+            Instruction::ConcatLists(dst, start, n) => {
+                let _ = write!(line, "ConcatLists r{dst} r{start} {n}");
+            }
+            Instruction::Return(register) => {
+                let _ = write!(line, "Return r{register}");
+            }
+        }
+        out.push_str(&line);
+        out.push('\n');
+    }
+}
+
+fn write_function(out: &mut String, mut line: String, function: &Function, indent: usize) {
+    let Function::User {
+        name,
+        args,
+        captures,
+        bytecode,
+        ..
+    } = function
+    else {
+        let _ = writeln!(line, "DefineFunction {}  ; native", function.name());
+        out.push_str(&line);
+        return;
+    };
+
+    let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+    let captures = captures
+        .iter()
+        .map(|capture| match capture {
+            Capture::Local(register) => format!("(local r{register})"),
+            Capture::Upvalue(index) => format!("(upvalue {index})"),
+        })
+        .collect::<Vec<_>>();
+    let _ = writeln!(
+        line,
+        "DefineFunction {name} ({}) ({})",
+        args.join(" "),
+        captures.join(" ")
+    );
+    out.push_str(&line);
+
+    // The body lines up under the instruction name
+    write_code(out, bytecode, &[], indent + 10);
+    let _ = writeln!(out, "{}end", " ".repeat(indent + 6));
+}
+
+fn op_name(op: &Op) -> &'static str {
+    match op {
+        Op::Add => "+",
+        Op::Sub => "-",
+        Op::Mul => "*",
+        Op::Div => "/",
+    }
+}
+
+/// `value` as an expression that evaluates to it. Lists holding `nil` or
+/// booleans are written as calls to `list`, as quoted they would read back
+/// as symbols.
+fn literal(value: &Value) -> String {
+    match value {
+        Value::List(items) if !quotable(value) => {
+            let items = items.iter().map(literal).collect::<Vec<_>>();
+            format!("(list {})", items.join(" "))
+        }
+        Value::Symbol(_) | Value::List(_) => format!("'{}", datum(value)),
+        _ => datum(value),
+    }
+}
+
+/// Whether the reader reads the [`datum`] of `value` back as `value`.
+fn quotable(value: &Value) -> bool {
+    match value {
+        Value::Nil | Value::Bool(_) => false,
+        Value::List(items) => items.iter().all(quotable),
+        _ => true,
+    }
+}
+
+/// `value` as the reader would read it back, if it is [`quotable`].
+fn datum(value: &Value) -> String {
+    match value {
+        Value::String(s) => format!("\"{s}\""),
+        Value::List(items) => {
+            let items = items.iter().map(datum).collect::<Vec<_>>();
+            format!("({})", items.join(" "))
+        }
+        _ => value.to_string(),
+    }
+}
+
+/// Parses the text written by [`disassemble`] back into bytecode.
+pub fn assemble(src: &str) -> Result<Vec<Instruction>> {
+    let data = read(src)?;
+    let mut data = data.iter();
+    assemble_code(&mut data, false, 0)
+}
+
+type Data<'a> = std::slice::Iter<'a, Value>;
+
+/// Reads instructions up to the end of `data`, or for a function body, up to
+/// and including its `end`. The code can load `upvalues` upvalues, which is
+/// how many its function captures, and none at the top level.
+fn assemble_code(data: &mut Data, body: bool, upvalues: usize) -> Result<Vec<Instruction>> {
+    let mut code = Vec::new();
+    while let Some(datum) = data.next() {
+        let mnemonic = match datum {
+            Value::Signed32(offset) => {
+                if usize::try_from(*offset).ok() != Some(code.len()) {
+                    return Err(Error::Expected("instruction offsets in order").into());
+                }
+                data.next()
+                    .ok_or(Error::Expected("instruction after offset"))?
+            }
+            _ => datum,
+        };
+        let Value::Symbol(mnemonic) = mnemonic else {
+            return Err(Error::Expected("instruction name").into());
+        };
+
+        code.push(match &**mnemonic {
+            "end" if body => return Ok(code),
+            "Load" => Instruction::Load(register(data)?, constant(operand(data)?)?),
+            "Move" => Instruction::Move(register(data)?, register(data)?),
+            "Operation" => {
+                let op = match &*symbol(data)? {
+                    "+" => Op::Add,
+                    "-" => Op::Sub,
+                    "*" => Op::Mul,
+                    "/" => Op::Div,
+                    _ => return Err(Error::Expected("one of + - * /").into()),
+                };
+                Instruction::Operation(op, register(data)?, register(data)?, register(data)?)
+            }
+            "Call" => Instruction::Call(
+                register(data)?,
+                SymbolId::intern(&symbol(data)?),
+                register(data)?,
+                index(data)?,
+            ),
+            "CallValue" => Instruction::CallValue(
+                register(data)?,
+                register(data)?,
+                register(data)?,
+                index(data)?,
+            ),
+            "LoadUpvalue" => {
+                Instruction::LoadUpvalue(register(data)?, upvalue(index(data)?, upvalues)?)
+            }
+            "LoadGlobal" => {
+                Instruction::LoadGlobal(register(data)?, SymbolId::intern(&symbol(data)?))
+            }
+            "DefineFunction" => Instruction::DefineFunction(Rc::new(function(data, upvalues)?)),
+            "Jump" => Instruction::Jump(count(data)?),
+            "JumpUnless" => Instruction::JumpUnless(register(data)?, count(data)?),
+            "MakeList" => Instruction::MakeList(register(data)?, register(data)?, index(data)?),
+            "ConcatLists" => {
+                Instruction::ConcatLists(register(data)?, register(data)?, index(data)?)
+            }
+            "Return" => Instruction::Return(register(data)?),
+            _ => return Err(Error::UnknownInstruction(mnemonic.to_string()).into()),
+        });
+    }
+    match body {
+        true => Err(Error::Expected("end of function").into()),
+        false => Ok(code),
+    }
+}
+
+fn operand<'a>(data: &mut Data<'a>) -> Result<&'a Value> {
+    data.next().ok_or_else(|| Error::Expected("operand").into())
+}
+
+fn symbol(data: &mut Data) -> Result<Rc<str>> {
+    match operand(data)? {
+        Value::Symbol(name) => Ok(name.clone()),
+        _ => Err(Error::Expected("name").into()),
+    }
+}
+
+fn count(data: &mut Data) -> Result<usize> {
+    match operand(data)? {
+        Value::Signed32(n) => Ok(usize::try_from(*n)?),
+        _ => Err(Error::Expected("count").into()),
+    }
+}
+
+fn index(data: &mut Data) -> Result<u16> {
+    match operand(data)? {
+        Value::Signed32(n) => Ok(u16::try_from(*n)?),
+        _ => Err(Error::Expected("index").into()),
+    }
+}
+
+/// `index`, if it is below the number of `upvalues` there are.
+fn upvalue(index: u16, upvalues: usize) -> Result<u16> {
+    match (index as usize) < upvalues {
+        true => Ok(index),
+        false => Err(Error::Expected("index of a captured upvalue").into()),
+    }
+}
+
+fn register(data: &mut Data) -> Result<Register> {
+    match operand(data)? {
+        Value::Symbol(name) => parse_register(name),
+        _ => Err(Error::Expected("register").into()),
+    }
+}
+
+/// `rn` as register `n`.
+fn parse_register(name: &str) -> Result<Register> {
+    name.strip_prefix('r')
+        .filter(|n| n.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| Error::Expected("register").into())
+}
+
+/// The value of a constant written by [`literal`].
+fn constant(operand: &Value) -> Result<Value> {
+    Ok(match operand {
+        Value::Symbol(name) => match &**name {
+            "nil" => Value::Nil,
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ => return Err(Error::Expected("quoted symbol").into()),
+        },
+        Value::List(items) => match &items[..] {
+            [Value::Symbol(quote), quoted] if &**quote == "quote" => quoted.clone(),
+            [Value::Symbol(list), items @ ..] if &**list == "list" => {
+                Value::List(items.iter().map(constant).collect::<Result<_>>()?)
+            }
+            _ => return Err(Error::Expected("quoted list").into()),
+        },
+        other => other.clone(),
+    })
+}
+
+/// `name (args...) (captures...)`, then the body up to `end`, defined in
+/// code that can load `upvalues` upvalues.
+fn function(data: &mut Data, upvalues: usize) -> Result<Function> {
+    let name = symbol(data)?.to_string();
+    let args = operand(data)?
+        .as_list()
+        .ok_or(Error::Expected("list of parameters"))?
+        .iter()
+        .map(|arg| match arg {
+            Value::Symbol(arg) => Ok(SymbolId::intern(arg)),
+            _ => Err(Error::Expected("identifier").into()),
+        })
+        .collect::<Result<_>>()?;
+    let captures: Vec<Capture> = operand(data)?
+        .as_list()
+        .ok_or(Error::Expected("list of captures"))?
+        .iter()
+        .map(|capture| {
+            let capture = capture.as_list().unwrap_or_default();
+            match capture {
+                [Value::Symbol(kind), Value::Symbol(register)] if &**kind == "local" => {
+                    Ok(Capture::Local(parse_register(register)?))
+                }
+                [Value::Symbol(kind), Value::Signed32(n)] if &**kind == "upvalue" => {
+                    Ok(Capture::Upvalue(upvalue(u16::try_from(*n)?, upvalues)?))
+                }
+                _ => Err(Error::Expected("(local rn) or (upvalue n)").into()),
+            }
+        })
+        .collect::<Result<_>>()?;
+    let bytecode = assemble_code(data, true, captures.len())?;
+
+    Ok(Function::User {
+        name,
+        args,
+        captures,
+        upvalues: RefCell::default(),
+        bytecode,
+    })
+}
@@ -1,49 +1,153 @@
+use crate::lexer::lex_lossless;
+use crate::lexer::lex_spanned;
+use crate::lexer::Span;
 use crate::lexer::Symbol;
 use crate::lexer::Token;
+use crate::Diagnostic;
 use crate::Error;
 use anyhow::Result;
 
-fn take_expr<'a>(toks: &'a [Token]) -> Result<(&'a [Token<'a>], &'a [Token<'a>])> {
-    match toks[0] {
-        Token::Symbol(_) => Ok((&toks[0..1], &toks[1..])),
-        Token::Open => {
-            let mut scope = 0;
-            for (i, t) in toks.iter().enumerate() {
-                match t {
-                    Token::Open => scope += 1,
-                    Token::Close => scope -= 1,
-                    _ => {}
+static QUOTE: Symbol<'static> = Symbol::Ident("quote");
+static QUASIQUOTE: Symbol<'static> = Symbol::Ident("quasiquote");
+static UNQUOTE: Symbol<'static> = Symbol::Ident("unquote");
+static UNQUOTE_SPLICING: Symbol<'static> = Symbol::Ident("unquote-splicing");
+
+/// The symbol a quote prefix token desugars to, `'x` becoming `(quote x)`.
+fn prefix_symbol(tok: &Token) -> Option<&'static Symbol<'static>> {
+    match tok {
+        Token::Quote => Some(&QUOTE),
+        Token::Quasiquote => Some(&QUASIQUOTE),
+        Token::Unquote => Some(&UNQUOTE),
+        Token::UnquoteSplicing => Some(&UNQUOTE_SPLICING),
+        _ => None,
+    }
+}
+
+/// An expression still being built by [`construct`], with where it started.
+enum Frame<'a> {
+    List(Vec<Tree<'a>>, Span),
+    /// A quote prefix waiting for the expression it applies to.
+    Prefix(&'static Symbol<'static>, Span),
+    /// A `#;` waiting for the expression it comments out.
+    Skip(Span),
+}
+
+struct Located<'a> {
+    tok: &'a Token<'a>,
+    span: Span,
+    line_start: bool,
+}
+
+/// Builds a branch holding every top-level form in a single pass, collecting
+/// errors rather than stopping at the first one. Nesting is tracked on an
+/// explicit stack, so deep input can't overflow the call stack.
+///
+/// With `split_at_line_starts`, an `(` at the very start of a line inside an
+/// unfinished form is taken to begin a new top-level form, which is how code
+/// is normally laid out. The unfinished form is reported and dropped.
+fn construct<'a>(
+    toks: impl Iterator<Item = Located<'a>>,
+    split_at_line_starts: bool,
+) -> (Tree<'a>, Vec<(Error, Span)>) {
+    let mut stack = vec![Frame::List(Vec::new(), Span { start: 0, end: 0 })];
+    let mut errors = Vec::new();
+
+    'tokens: for Located {
+        tok,
+        span,
+        line_start,
+    } in toks
+    {
+        if split_at_line_starts && line_start && *tok == Token::Open {
+            let unfinished = stack[1..].iter().find_map(|frame| match frame {
+                Frame::List(_, open) => Some(*open),
+                Frame::Prefix(..) | Frame::Skip(_) => None,
+            });
+            if let Some(open) = unfinished {
+                errors.push((Error::UnmatchedOpenExpr, open));
+                stack.truncate(1);
+            }
+        }
+
+        let mut node = match tok {
+            Token::Whitespace(_) | Token::Comment(_) => continue,
+            Token::Symbol(sym) => Tree::Leaf(sym),
+            Token::Open => {
+                stack.push(Frame::List(Vec::new(), span));
+                continue;
+            }
+            Token::Close => loop {
+                match stack.pop() {
+                    Some(Frame::Prefix(_, at)) => {
+                        errors.push((Error::Expected("expression after quote"), at))
+                    }
+                    Some(Frame::Skip(at)) => {
+                        errors.push((Error::Expected("expression after #;"), at))
+                    }
+                    Some(Frame::List(children, _)) if !stack.is_empty() => {
+                        break Tree::Branch(children)
+                    }
+                    Some(top_level) => {
+                        stack.push(top_level);
+                        errors.push((Error::UnmatchedCloseExpr, span));
+                        continue 'tokens;
+                    }
+                    None => unreachable!("the top-level frame is never popped"),
+                }
+            },
+            Token::DatumComment => {
+                stack.push(Frame::Skip(span));
+                continue;
+            }
+            prefix => {
+                let sym = prefix_symbol(prefix).expect("only prefixes are left");
+                stack.push(Frame::Prefix(sym, span));
+                continue;
+            }
+        };
+
+        // Close every quote prefix the finished expression completes, up to a
+        // datum comment that drops it
+        loop {
+            match stack.last_mut() {
+                Some(Frame::Prefix(sym, _)) => {
+                    node = Tree::Branch(vec![Tree::Leaf(sym), node]);
+                    stack.pop();
+                }
+                Some(Frame::Skip(_)) => {
+                    stack.pop();
+                    break;
                 }
-                if scope == 0 {
-                    return Ok((&toks[0..i + 1], &toks[i + 1..]));
+                Some(Frame::List(children, _)) => {
+                    children.push(node);
+                    break;
                 }
+                None => unreachable!("the top-level frame is never popped"),
             }
-            Err(Error::UnmatchedOpenExpr.into())
         }
-        _ => Err(Error::Expected("( or symbol)").into()),
     }
-}
 
-fn take_toplevel_exprs<'a>(toks: &'a [Token]) -> Result<Vec<&'a [Token<'a>]>> {
-    if toks.is_empty() {
-        return Err(Error::Expected("nonempty list").into());
+    // Whatever is still open at the end of the input, outermost first
+    for frame in stack.drain(1..) {
+        match frame {
+            Frame::List(_, open) => errors.push((Error::UnmatchedOpenExpr, open)),
+            Frame::Prefix(_, at) => errors.push((Error::Expected("expression after quote"), at)),
+            Frame::Skip(at) => errors.push((Error::Expected("expression after #;"), at)),
+        }
     }
-
-    let mut exprs = Vec::new();
-    let mut tail = toks;
-    while !tail.is_empty() {
-        let head;
-        (head, tail) = take_expr(tail)?;
-        exprs.push(head);
+    match stack.pop() {
+        Some(Frame::List(forms, _)) => (Tree::Branch(forms), errors),
+        _ => unreachable!("the top-level frame is a list"),
     }
-    Ok(exprs)
 }
 
-fn slice_middle<'a, T>(slc: &'a [T]) -> Option<&'a [T]> {
-    if slc.len() < 3 {
-        return None;
-    }
-    Some(&slc[1..slc.len() - 1])
+/// Lexes and parses `src`, reporting every syntax error in it in source order.
+pub fn check(src: &str) -> Vec<Diagnostic> {
+    let (tokens, mut diagnostics) = lex_spanned(src);
+    let (_, errors) = Tree::construct_recovering(src, &tokens);
+    diagnostics.extend(errors);
+    diagnostics.sort_by_key(|diagnostic| diagnostic.span.start);
+    diagnostics
 }
 
 #[derive(Debug, PartialEq)]
@@ -53,34 +157,175 @@ pub enum Tree<'a> {
 }
 
 impl Tree<'_> {
+    /// Parses `toks` into a branch holding every top-level form.
     pub fn try_construct<'a>(toks: &'a [Token]) -> Result<Tree<'a>> {
-        match toks {
-            [Token::Symbol(sym)] => Ok(Tree::Leaf(sym)),
-            _ => {
-                let expressions = take_toplevel_exprs(toks)?;
-                let tree: Result<Vec<_>, _> = expressions
-                    .into_iter()
-                    .map(|e| match slice_middle(e) {
-                        Some(middle) => Self::try_construct(middle),
-                        None => Self::try_construct(e),
-                    })
-                    .collect();
-                Ok(Tree::Branch(tree?))
-            }
+        let located = toks.iter().map(|tok| Located {
+            tok,
+            span: Span { start: 0, end: 0 },
+            line_start: false,
+        });
+        match construct(located, false) {
+            (_, errors) if !errors.is_empty() => Err(errors.into_iter().next().unwrap().0.into()),
+            (tree, _) => Ok(tree),
         }
     }
 
-    pub fn branch(&self) -> Option<&[Tree]> {
+    /// Like [`Tree::try_construct`], but reports every error it finds in the
+    /// tokens lexed from `src`, skipping past them to keep parsing.
+    pub fn construct_recovering<'a>(
+        src: &str,
+        toks: &'a [(Token<'a>, Span)],
+    ) -> (Tree<'a>, Vec<Diagnostic>) {
+        let located = || {
+            toks.iter().map(|(tok, span)| Located {
+                tok,
+                span: *span,
+                line_start: span.start == 0 || src.as_bytes()[span.start - 1] == b'\n',
+            })
+        };
+
+        // Splitting at line starts can misreport oddly laid out code, so it is
+        // only used to pin down where a missing ')' belongs.
+        let (mut tree, mut errors) = construct(located(), false);
+        if errors
+            .iter()
+            .any(|(error, _)| matches!(error, Error::UnmatchedOpenExpr))
+        {
+            (tree, errors) = construct(located(), true);
+        }
+
+        let diagnostics = errors
+            .into_iter()
+            .map(|(error, span)| Diagnostic::new(src, span, error))
+            .collect();
+        (tree, diagnostics)
+    }
+
+    pub fn branch(&self) -> Option<&[Tree<'_>]> {
         match self {
             Tree::Branch(children) => Some(children),
             _ => None,
         }
     }
 
-    pub fn leaf(&self) -> Option<&Symbol> {
+    pub fn leaf(&self) -> Option<&Symbol<'_>> {
         match self {
             Tree::Leaf(sym) => Some(sym),
             _ => None,
         }
     }
 }
+
+/// Lossless concrete syntax tree. Unlike [`Tree`] it keeps every byte of the
+/// source, comments and whitespace included, so that printing it gives back
+/// the exact input. This is what tools that rewrite code work on.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Cst<'a> {
+    /// The nodes between a `(` and its `)`.
+    List(Vec<Cst<'a>>),
+    /// A quote prefix or `#;` followed by any trivia and then the expression
+    /// it applies to, which is always the last node.
+    Quoted(&'a str, Vec<Cst<'a>>),
+    /// A symbol, number or string literal, as written.
+    Atom(&'a str),
+    Whitespace(&'a str),
+    Comment(&'a str),
+}
+
+enum CstFrame<'a> {
+    List(Vec<Cst<'a>>),
+    Quoted(&'a str, Vec<Cst<'a>>),
+}
+
+impl<'a> Cst<'a> {
+    /// Parses `src` into its top-level nodes, trivia included.
+    pub fn parse(src: &'a str) -> Result<Vec<Cst<'a>>> {
+        let mut stack = vec![CstFrame::List(Vec::new())];
+
+        for (tok, span) in lex_lossless(src)? {
+            let text = &src[span.start..span.end];
+            let mut node = match tok {
+                Token::Whitespace(text) => Cst::Whitespace(text),
+                Token::Comment(text) => Cst::Comment(text),
+                Token::Symbol(_) => Cst::Atom(text),
+                Token::Open => {
+                    stack.push(CstFrame::List(Vec::new()));
+                    continue;
+                }
+                Token::Close => match stack.pop() {
+                    Some(CstFrame::List(children)) if !stack.is_empty() => Cst::List(children),
+                    Some(CstFrame::Quoted(..)) => {
+                        return Err(Error::Expected("expression after quote").into())
+                    }
+                    _ => return Err(Error::UnmatchedCloseExpr.into()),
+                },
+                Token::Quote
+                | Token::Quasiquote
+                | Token::Unquote
+                | Token::UnquoteSplicing
+                | Token::DatumComment => {
+                    stack.push(CstFrame::Quoted(text, Vec::new()));
+                    continue;
+                }
+            };
+
+            // Trivia never completes a quote, expressions close all pending ones
+            loop {
+                match stack.last_mut() {
+                    Some(CstFrame::Quoted(_, inner)) if node.is_trivia() => {
+                        inner.push(node);
+                        break;
+                    }
+                    Some(CstFrame::Quoted(prefix, inner)) => {
+                        inner.push(node);
+                        node = Cst::Quoted(prefix, std::mem::take(inner));
+                        stack.pop();
+                    }
+                    Some(CstFrame::List(children)) => {
+                        children.push(node);
+                        break;
+                    }
+                    None => unreachable!("the top-level frame is never popped"),
+                }
+            }
+        }
+
+        match (stack.pop(), stack.is_empty()) {
+            (Some(CstFrame::List(nodes)), true) => Ok(nodes),
+            (Some(CstFrame::Quoted(..)), _) => {
+                Err(Error::Expected("expression after quote").into())
+            }
+            _ => Err(Error::UnmatchedOpenExpr.into()),
+        }
+    }
+
+    /// Whitespace and comments, including expressions commented out by `#;`.
+    pub fn is_trivia(&self) -> bool {
+        matches!(
+            self,
+            Cst::Whitespace(_) | Cst::Comment(_) | Cst::Quoted("#;", _)
+        )
+    }
+}
+
+impl std::fmt::Display for Cst<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Cst::List(children) => {
+                write!(f, "(")?;
+                for child in children {
+                    write!(f, "{child}")?;
+                }
+                write!(f, ")")
+            }
+            Cst::Quoted(prefix, inner) => {
+                write!(f, "{prefix}")?;
+                for node in inner {
+                    write!(f, "{node}")?;
+                }
+                Ok(())
+            }
+            Cst::Atom(text) | Cst::Whitespace(text) | Cst::Comment(text) => write!(f, "{text}"),
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use crate::simulator::Function;
+use crate::simulator::Op;
+use crate::simulator::Value;
+use crate::simulator::Vm;
+use crate::Error;
+
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+use anyhow::Result;
+
+pub(crate) fn register_all(vm: &mut Vm) {
+    vm.register_builtin("echo", builtin_echo);
+
+    vm.register_builtin("+", |_, args| Op::Add.apply(args));
+    vm.register_builtin("-", |_, args| Op::Sub.apply(args));
+    vm.register_builtin("*", |_, args| Op::Mul.apply(args));
+    vm.register_builtin("/", |_, args| Op::Div.apply(args));
+
+    vm.register_builtin("=", builtin_eq);
+    vm.register_builtin("<", |_, args| compare(args, Ordering::is_lt));
+    vm.register_builtin(">", |_, args| compare(args, Ordering::is_gt));
+    vm.register_builtin("<=", |_, args| compare(args, Ordering::is_le));
+    vm.register_builtin(">=", |_, args| compare(args, Ordering::is_ge));
+    vm.register_builtin("not", builtin_not);
+
+    vm.register_builtin("list", builtin_list);
+    vm.register_builtin("cons", builtin_cons);
+    vm.register_builtin("first", builtin_first);
+    vm.register_builtin("rest", builtin_rest);
+    vm.register_builtin("empty?", builtin_is_empty);
+    vm.register_builtin("length", builtin_length);
+
+    vm.register_builtin("range", builtin_range);
+    vm.register_builtin("map", builtin_map);
+    vm.register_builtin("filter", builtin_filter);
+    vm.register_builtin("reduce", builtin_reduce);
+    vm.register_builtin("apply", builtin_apply);
+    vm.register_builtin("for-each", builtin_for_each);
+    vm.register_builtin("sort-by", builtin_sort_by);
+    vm.register_builtin("compose", builtin_compose);
+
+    vm.register_builtin("gensym", builtin_gensym);
+}
+
+fn expect_args<const N: usize>(args: Vec<Value>) -> Result<[Value; N]> {
+    args.try_into()
+        .map_err(|args: Vec<_>| Error::UnexpectedArgN(N, args.len()).into())
+}
+
+fn expect_list(value: &Value) -> Result<&[Value]> {
+    value.as_list().ok_or(Error::Expected("list").into())
+}
+
+fn builtin_echo(_: &mut Vm, args: Vec<Value>) -> Result<Value> {
+    let [a] = expect_args(args)?;
+    println!("{}", a);
+    Ok(Value::Nil)
+}
+
+fn builtin_eq(_: &mut Vm, args: Vec<Value>) -> Result<Value> {
+    let [a, b] = expect_args(args)?;
+    Ok(Value::Bool(a == b))
+}
+
+fn compare(args: Vec<Value>, test: fn(Ordering) -> bool) -> Result<Value> {
+    let [a, b] = expect_args(args)?;
+    Ok(Value::Bool(test(a.compare(&b)?)))
+}
+
+fn builtin_not(_: &mut Vm, args: Vec<Value>) -> Result<Value> {
+    let [a] = expect_args(args)?;
+    Ok(Value::Bool(!a.is_truthy()))
+}
+
+fn builtin_list(vm: &mut Vm, args: Vec<Value>) -> Result<Value> {
+    vm.alloc_list(args)
+}
+
+fn builtin_cons(vm: &mut Vm, args: Vec<Value>) -> Result<Value> {
+    let [head, tail] = expect_args(args)?;
+    let items = std::iter::once(head)
+        .chain(expect_list(&tail)?.iter().cloned())
+        .collect::<Vec<_>>();
+    vm.alloc_list(items)
+}
+
+fn builtin_first(_: &mut Vm, args: Vec<Value>) -> Result<Value> {
+    let [list] = expect_args(args)?;
+    Ok(expect_list(&list)?.first().cloned().unwrap_or(Value::Nil))
+}
+
+fn builtin_rest(vm: &mut Vm, args: Vec<Value>) -> Result<Value> {
+    let [list] = expect_args(args)?;
+    let items = expect_list(&list)?;
+    vm.alloc_list(items.get(1..).unwrap_or_default())
+}
+
+fn builtin_is_empty(_: &mut Vm, args: Vec<Value>) -> Result<Value> {
+    let [list] = expect_args(args)?;
+    Ok(Value::Bool(expect_list(&list)?.is_empty()))
+}
+
+fn builtin_length(_: &mut Vm, args: Vec<Value>) -> Result<Value> {
+    let [list] = expect_args(args)?;
+    Ok(Value::Signed32(expect_list(&list)?.len() as i32))
+}
+
+/// `(range from to)`, the integers from `from` up to, but not including, `to`.
+fn builtin_range(vm: &mut Vm, args: Vec<Value>) -> Result<Value> {
+    let [from, to] = expect_args(args)?;
+    let (Value::Signed32(from), Value::Signed32(to)) = (from, to) else {
+        return Err(Error::Expected("two numbers").into());
+    };
+    if from >= to {
+        return Ok(Value::Nil);
+    }
+    // Checked first, as the list may be far too big to build
+    vm.make_room((to as i64 - from as i64) as usize)?;
+    vm.alloc_list((from..to).map(Value::Signed32).collect::<Vec<_>>())
+}
+
+fn builtin_map(vm: &mut Vm, args: Vec<Value>) -> Result<Value> {
+    let [f, list] = expect_args(args)?;
+    let items = expect_list(&list)?
+        .iter()
+        .map(|item| vm.call(&f, vec![item.clone()]))
+        .collect::<Result<Vec<_>>>()?;
+    vm.alloc_list(items)
+}
+
+fn builtin_filter(vm: &mut Vm, args: Vec<Value>) -> Result<Value> {
+    let [predicate, list] = expect_args(args)?;
+    let mut items = Vec::new();
+    for item in expect_list(&list)? {
+        if vm.call(&predicate, vec![item.clone()])?.is_truthy() {
+            items.push(item.clone());
+        }
+    }
+    vm.alloc_list(items)
+}
+
+fn builtin_reduce(vm: &mut Vm, args: Vec<Value>) -> Result<Value> {
+    let [f, init, list] = expect_args(args)?;
+    expect_list(&list)?
+        .iter()
+        .try_fold(init, |acc, item| vm.call(&f, vec![acc, item.clone()]))
+}
+
+fn builtin_apply(vm: &mut Vm, args: Vec<Value>) -> Result<Value> {
+    let [f, list] = expect_args(args)?;
+    vm.call(&f, expect_list(&list)?.to_vec())
+}
+
+fn builtin_for_each(vm: &mut Vm, args: Vec<Value>) -> Result<Value> {
+    let [f, list] = expect_args(args)?;
+    for item in expect_list(&list)? {
+        vm.call(&f, vec![item.clone()])?;
+    }
+    Ok(Value::Nil)
+}
+
+fn builtin_sort_by(vm: &mut Vm, args: Vec<Value>) -> Result<Value> {
+    let [key, list] = expect_args(args)?;
+    let mut keyed = expect_list(&list)?
+        .iter()
+        .map(|item| Ok((vm.call(&key, vec![item.clone()])?, item.clone())))
+        .collect::<Result<Vec<_>>>()?;
+
+    // `sort_by` can't propagate errors, so check comparability up front.
+    for pair in keyed.windows(2) {
+        pair[0].0.compare(&pair[1].0)?;
+    }
+    keyed.sort_by(|(a, _), (b, _)| a.compare(b).unwrap_or(Ordering::Equal));
+
+    let items = keyed.into_iter().map(|(_, item)| item).collect::<Vec<_>>();
+    vm.alloc_list(items)
+}
+
+/// `(compose f g h)` builds a function that applies `h`, then `g`, then `f`.
+fn builtin_compose(vm: &mut Vm, args: Vec<Value>) -> Result<Value> {
+    if args.is_empty() {
+        return Err(Error::UnexpectedArgN(1, 0).into());
+    }
+
+    let composed = Function::Native {
+        name: "compose".to_string(),
+        inner: Rc::new(move |vm, values| {
+            let (innermost, outer) = args.split_last().expect("compose has arguments");
+            let first = vm.call(innermost, values)?;
+            outer
+                .iter()
+                .rev()
+                .try_fold(first, |value, f| vm.call(f, vec![value]))
+        }),
+    };
+    Ok(Value::Function(vm.alloc_function(composed)?))
+}
+
+/// `(gensym)` or `(gensym 'prefix)`.
+fn builtin_gensym(vm: &mut Vm, args: Vec<Value>) -> Result<Value> {
+    let prefix = match args.as_slice() {
+        [] => "g".to_string(),
+        [Value::Symbol(prefix) | Value::String(prefix)] => prefix.to_string(),
+        [_] => return Err(Error::Expected("symbol or string prefix").into()),
+        _ => return Err(Error::UnexpectedArgN(1, args.len()).into()),
+    };
+    Ok(Value::Symbol(vm.gensym(&prefix).into()))
+}
@@ -0,0 +1,467 @@
+//! The `.lbc` file format, for running programs without compiling them first.
+//!
+//! All integers are little-endian. A file is
+//!
+//! ```text
+//! magic      "LBC\0"
+//! version    u16
+//! symbols    u32 count, then each name as a u32 length and UTF-8 bytes
+//! constants  u32 count, then each value
+//! functions  u32 count, then each function
+//! code       the top-level instructions
+//! ```
+//!
+//! Symbol IDs are only meaningful in the process that interned them, so
+//! instructions refer to names by their index in the symbol table, and the
+//! names are interned again when the file is read. `Load` refers to the
+//! constant pool, and `DefineFunction` to the function table, where a
+//! function only defines functions that come before it. A function defined
+//! in several places is written once, and shared again when read, so a
+//! small file can't decode into a huge program. Registers and
+//! argument counts are u16s, jump offsets u32s.
+//!
+//! Decoding checks everything the VM would otherwise trust: indices into
+//! the tables, upvalue indices against the captures of the code they are
+//! in, and how deeply constants nest.
+
+use crate::intern::SymbolId;
+use crate::resolver::Capture;
+use crate::simulator::Function;
+use crate::simulator::Instruction;
+use crate::simulator::Op;
+use crate::simulator::Value;
+use crate::Error;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use anyhow::Result;
+
+pub const MAGIC: &[u8; 4] = b"LBC\0";
+
+/// Bumped whenever the encoding changes; files of other versions are
+/// rejected rather than misread.
+pub const VERSION: u16 = 2;
+
+/// How deeply lists in constants may nest, so reading and dropping them
+/// can't overflow the stack.
+const MAX_NESTING: usize = 256;
+
+/// Encodes top-level bytecode, with every function it defines.
+pub fn encode(bytecode: &[Instruction]) -> Result<Vec<u8>> {
+    let mut writer = Writer::default();
+    let code = writer.code(bytecode)?;
+
+    let mut out = MAGIC.to_vec();
+    out.extend(VERSION.to_le_bytes());
+    put_len(&mut out, writer.symbols.len())?;
+    for symbol in &writer.symbols {
+        put_str(&mut out, &symbol.name())?;
+    }
+    put_len(&mut out, writer.constants.len())?;
+    for constant in &writer.constants {
+        put_value(&mut out, constant, 0)?;
+    }
+    put_len(&mut out, writer.functions.len())?;
+    for function in &writer.functions {
+        out.extend(function);
+    }
+    out.extend(code);
+    Ok(out)
+}
+
+/// Decodes a file written by [`encode`] back into top-level bytecode.
+pub fn decode(bytes: &[u8]) -> Result<Vec<Instruction>> {
+    let mut reader = Reader {
+        bytes,
+        pos: 0,
+        symbols: Vec::new(),
+        constants: Vec::new(),
+        functions: Vec::new(),
+    };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(Error::InvalidBytecode("not a bytecode file").into());
+    }
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(Error::BytecodeVersion(version).into());
+    }
+
+    for _ in 0..reader.u32()? {
+        let name = reader.str()?;
+        reader.symbols.push(SymbolId::intern(name));
+    }
+    for _ in 0..reader.u32()? {
+        let value = reader.value(0)?;
+        reader.constants.push(value);
+    }
+    for _ in 0..reader.u32()? {
+        let function = reader.function()?;
+        reader.functions.push(Rc::new(function));
+    }
+    // Top-level code has no upvalues
+    let code = reader.code(0)?;
+
+    if reader.pos != bytes.len() {
+        return Err(Error::InvalidBytecode("trailing bytes").into());
+    }
+    Ok(code)
+}
+
+#[derive(Default)]
+struct Writer {
+    symbols: Vec<SymbolId>,
+    constants: Vec<Value>,
+    /// Encoded functions, each after the ones it defines.
+    functions: Vec<Vec<u8>>,
+    /// Indices of the functions written so far, so a function defined in
+    /// many places is written once.
+    written: HashMap<*const Function, u32>,
+}
+
+impl Writer {
+    fn symbol(&mut self, symbol: SymbolId) -> u32 {
+        let index = match self.symbols.iter().position(|s| *s == symbol) {
+            Some(index) => index,
+            None => {
+                self.symbols.push(symbol);
+                self.symbols.len() - 1
+            }
+        };
+        index as u32
+    }
+
+    fn constant(&mut self, value: &Value) -> u32 {
+        let index = match self.constants.iter().position(|c| c == value) {
+            Some(index) => index,
+            None => {
+                self.constants.push(value.clone());
+                self.constants.len() - 1
+            }
+        };
+        index as u32
+    }
+
+    fn function(&mut self, function: &Rc<Function>) -> Result<u32> {
+        if let Some(index) = self.written.get(&Rc::as_ptr(function)) {
+            return Ok(*index);
+        }
+        let Function::User {
+            name,
+            args,
+            captures,
+            bytecode,
+            ..
+        } = &**function
+        else {
+            return Err(Error::InvalidBytecode("only user functions can be saved").into());
+        };
+
+        let mut out = Vec::new();
+        let name = self.symbol(SymbolId::intern(name));
+        out.extend(name.to_le_bytes());
+        put_len(&mut out, args.len())?;
+        for arg in args {
+            let arg = self.symbol(*arg);
+            out.extend(arg.to_le_bytes());
+        }
+        put_len(&mut out, captures.len())?;
+        for capture in captures {
+            let (kind, index) = match capture {
+                Capture::Local(register) => (0u8, register),
+                Capture::Upvalue(index) => (1, index),
+            };
+            out.push(kind);
+            out.extend(index.to_le_bytes());
+        }
+        out.extend(self.code(bytecode)?);
+
+        self.functions.push(out);
+        let index = self.functions.len() as u32 - 1;
+        self.written.insert(Rc::as_ptr(function), index);
+        Ok(index)
+    }
+
+    fn code(&mut self, bytecode: &[Instruction]) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        put_len(&mut out, bytecode.len())?;
+        for instruction in bytecode {
+            match instruction {
+                Instruction::Load(dst, value) => {
+                    out.push(0);
+                    out.extend(dst.to_le_bytes());
+                    out.extend(self.constant(value).to_le_bytes());
+                }
+                Instruction::Move(dst, src) => {
+                    out.push(1);
+                    put_u16s(&mut out, &[*dst, *src]);
+                }
+                Instruction::Operation(op, dst, a, b) => {
+                    out.push(2);
+                    out.push(match op {
+                        Op::Add => 0,
+                        Op::Sub => 1,
+                        Op::Mul => 2,
+                        Op::Div => 3,
+                    });
+                    put_u16s(&mut out, &[*dst, *a, *b]);
+                }
+                Instruction::Call(dst, name, start, n) => {
+                    out.push(3);
+                    out.extend(dst.to_le_bytes());
+                    out.extend(self.symbol(*name).to_le_bytes());
+                    put_u16s(&mut out, &[*start, *n]);
+                }
+                Instruction::CallValue(dst, callee, start, n) => {
+                    out.push(4);
+                    put_u16s(&mut out, &[*dst, *callee, *start, *n]);
+                }
+                Instruction::LoadUpvalue(dst, index) => {
+                    out.push(5);
+                    put_u16s(&mut out, &[*dst, *index]);
+                }
+                Instruction::LoadGlobal(dst, name) => {
+                    out.push(6);
+                    out.extend(dst.to_le_bytes());
+                    out.extend(self.symbol(*name).to_le_bytes());
+                }
+                Instruction::DefineFunction(function) => {
+                    let index = self.function(function)?;
+                    out.push(7);
+                    out.extend(index.to_le_bytes());
+                }
+                Instruction::Jump(n) => {
+                    out.push(8);
+                    put_len(&mut out, *n)?;
+                }
+                Instruction::JumpUnless(condition, n) => {
+                    out.push(9);
+                    out.extend(condition.to_le_bytes());
+                    put_len(&mut out, *n)?;
+                }
+                Instruction::MakeList(dst, start, n) => {
+                    out.push(10);
+                    put_u16s(&mut out, &[*dst, *start, *n]);
+                }
+                Instruction::ConcatLists(dst, start, n) => {
+                    out.push(11);
+                    put_u16s(&mut out, &[*dst, *start, *n]);
+                }
+                Instruction::Return(register) => {
+                    out.push(12);
+                    out.extend(register.to_le_bytes());
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+fn put_len(out: &mut Vec<u8>, len: usize) -> Result<()> {
+    let len = u32::try_from(len).map_err(|_| Error::InvalidBytecode("length over u32"))?;
+    out.extend(len.to_le_bytes());
+    Ok(())
+}
+
+fn put_u16s(out: &mut Vec<u8>, values: &[u16]) {
+    for value in values {
+        out.extend(value.to_le_bytes());
+    }
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) -> Result<()> {
+    put_len(out, s.len())?;
+    out.extend(s.as_bytes());
+    Ok(())
+}
+
+fn put_value(out: &mut Vec<u8>, value: &Value, depth: usize) -> Result<()> {
+    if depth > MAX_NESTING {
+        return Err(Error::InvalidBytecode("constant nested too deeply").into());
+    }
+    match value {
+        Value::Nil => out.push(0),
+        Value::Bool(b) => out.extend([1, u8::from(*b)]),
+        Value::Signed32(n) => {
+            out.push(2);
+            out.extend(n.to_le_bytes());
+        }
+        Value::String(s) => {
+            out.push(3);
+            put_str(out, s)?;
+        }
+        Value::Symbol(s) => {
+            out.push(4);
+            put_str(out, s)?;
+        }
+        Value::List(items) => {
+            out.push(5);
+            put_len(out, items.len())?;
+            for item in items.iter() {
+                put_value(out, item, depth + 1)?;
+            }
+        }
+        Value::Function(_) => {
+            return Err(Error::InvalidBytecode("function values can't be constants").into())
+        }
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    symbols: Vec<SymbolId>,
+    constants: Vec<Value>,
+    /// Each read once, and shared by the code defining it.
+    functions: Vec<Rc<Function>>,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.pos..)
+            .and_then(|rest| rest.get(..n))
+            .ok_or(Error::InvalidBytecode("unexpected end of file"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().expect("took N bytes"))
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn str(&mut self) -> Result<&'a str> {
+        let len = self.len()?;
+        std::str::from_utf8(self.take(len)?)
+            .map_err(|_| Error::InvalidBytecode("string is not UTF-8").into())
+    }
+
+    fn symbol(&mut self) -> Result<SymbolId> {
+        let index = self.len()?;
+        self.symbols
+            .get(index)
+            .copied()
+            .ok_or_else(|| Error::InvalidBytecode("symbol index out of range").into())
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value> {
+        if depth > MAX_NESTING {
+            return Err(Error::InvalidBytecode("constant nested too deeply").into());
+        }
+        Ok(match self.u8()? {
+            0 => Value::Nil,
+            1 => Value::Bool(self.u8()? != 0),
+            2 => Value::Signed32(i32::from_le_bytes(self.array()?)),
+            3 => Value::String(self.str()?.into()),
+            4 => Value::Symbol(self.str()?.into()),
+            5 => {
+                let len = self.len()?;
+                let items = (0..len)
+                    .map(|_| self.value(depth + 1))
+                    .collect::<Result<Rc<[_]>>>()?;
+                Value::List(items)
+            }
+            _ => return Err(Error::InvalidBytecode("unknown value tag").into()),
+        })
+    }
+
+    fn function(&mut self) -> Result<Function> {
+        let name = self.symbol()?.name().to_string();
+        let args = (0..self.len()?)
+            .map(|_| self.symbol())
+            .collect::<Result<_>>()?;
+        let captures = (0..self.len()?)
+            .map(|_| match self.u8()? {
+                0 => Ok(Capture::Local(self.u16()?)),
+                1 => Ok(Capture::Upvalue(self.u16()?)),
+                _ => Err(Error::InvalidBytecode("unknown capture kind").into()),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let bytecode = self.code(captures.len())?;
+        Ok(Function::User {
+            name,
+            args,
+            captures,
+            upvalues: RefCell::default(),
+            bytecode,
+        })
+    }
+
+    /// Code with `upvalues` upvalues to load and capture from.
+    fn code(&mut self, upvalues: usize) -> Result<Vec<Instruction>> {
+        let upvalue = |index: u16| match (index as usize) < upvalues {
+            true => Ok(index),
+            false => Err(Error::InvalidBytecode("upvalue index out of range")),
+        };
+        let len = self.len()?;
+        let mut code = Vec::new();
+        for _ in 0..len {
+            code.push(match self.u8()? {
+                0 => {
+                    let dst = self.u16()?;
+                    let index = self.len()?;
+                    let value = self
+                        .constants
+                        .get(index)
+                        .ok_or(Error::InvalidBytecode("constant index out of range"))?;
+                    Instruction::Load(dst, value.clone())
+                }
+                1 => Instruction::Move(self.u16()?, self.u16()?),
+                2 => {
+                    let op = match self.u8()? {
+                        0 => Op::Add,
+                        1 => Op::Sub,
+                        2 => Op::Mul,
+                        3 => Op::Div,
+                        _ => return Err(Error::InvalidBytecode("unknown operation").into()),
+                    };
+                    Instruction::Operation(op, self.u16()?, self.u16()?, self.u16()?)
+                }
+                3 => Instruction::Call(self.u16()?, self.symbol()?, self.u16()?, self.u16()?),
+                4 => Instruction::CallValue(self.u16()?, self.u16()?, self.u16()?, self.u16()?),
+                5 => Instruction::LoadUpvalue(self.u16()?, upvalue(self.u16()?)?),
+                6 => Instruction::LoadGlobal(self.u16()?, self.symbol()?),
+                7 => {
+                    // Only functions read so far, so definitions can't loop
+                    let index = self.len()?;
+                    let function = self
+                        .functions
+                        .get(index)
+                        .ok_or(Error::InvalidBytecode("function index out of range"))?;
+                    for capture in function.captures() {
+                        if let Capture::Upvalue(index) = capture {
+                            upvalue(*index)?;
+                        }
+                    }
+                    Instruction::DefineFunction(Rc::clone(function))
+                }
+                8 => Instruction::Jump(self.len()?),
+                9 => Instruction::JumpUnless(self.u16()?, self.len()?),
+                10 => Instruction::MakeList(self.u16()?, self.u16()?, self.u16()?),
+                11 => Instruction::ConcatLists(self.u16()?, self.u16()?, self.u16()?),
+                12 => Instruction::Return(self.u16()?),
+                _ => return Err(Error::InvalidBytecode("unknown opcode").into()),
+            });
+        }
+        Ok(code)
+    }
+}
@@ -0,0 +1,163 @@
+use crate::simulator::create_user_function;
+use crate::simulator::generate;
+use crate::simulator::head_symbol;
+use crate::simulator::Ast;
+use crate::simulator::Value;
+use crate::simulator::Vm;
+use crate::Error;
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use anyhow::Result;
+
+/// The macro expansion pass, run on the datums produced by `read` before any
+/// code is generated. Macros are ordinary user functions from code to code,
+/// executed on a VM of their own that is only started once a macro is used.
+/// Top-level functions are defined on it too, so macros can call those
+/// defined before the macro is used.
+#[derive(Default)]
+pub struct Expander {
+    vm: Option<Vm>,
+    macros: HashMap<String, Value>,
+    /// Top-level `fn` forms waiting for the VM to start.
+    functions: Vec<Value>,
+}
+
+impl Expander {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expands every top-level form. `defmacro` forms define their macro for
+    /// the forms after them and are dropped from the output.
+    pub fn expand_all(&mut self, forms: Vec<Value>) -> Result<Vec<Value>> {
+        let mut expanded = Vec::new();
+        for form in forms {
+            match &form {
+                Value::List(items) if head_symbol(items) == Some("defmacro") => {
+                    self.define(items)?
+                }
+                _ => {
+                    let form = self.expand(&form)?;
+                    if let Value::List(items) = &form {
+                        if head_symbol(items) == Some("fn") {
+                            self.define_function(&form)?;
+                        }
+                    }
+                    expanded.push(form);
+                }
+            }
+        }
+        Ok(expanded)
+    }
+
+    /// Defines a top-level function for macros to call, once the VM runs.
+    fn define_function(&mut self, form: &Value) -> Result<()> {
+        match &mut self.vm {
+            Some(vm) => vm.run(&generate(std::slice::from_ref(form))?),
+            None => {
+                self.functions.push(form.clone());
+                Ok(())
+            }
+        }
+    }
+
+    /// Expands `form` until no macro calls are left in it.
+    pub fn expand(&mut self, form: &Value) -> Result<Value> {
+        let items = match form {
+            Value::List(items) => items,
+            _ => return Ok(form.clone()),
+        };
+
+        match (head_symbol(items), &items[..]) {
+            (Some("quote"), _) => Ok(form.clone()),
+            (Some("quasiquote"), [head, template]) => {
+                let template = self.expand_template(template, 1)?;
+                Ok(Value::List([head.clone(), template].into()))
+            }
+            (Some("defmacro"), _) => Err(Error::Expected("defmacro at top level").into()),
+            // Leave the name and parameters alone, they are not code
+            (Some("fn"), [head, name, params, body]) => {
+                let body = self.expand(body)?;
+                Ok(Value::List(
+                    [head.clone(), name.clone(), params.clone(), body].into(),
+                ))
+            }
+            (Some(name), _) if self.macros.contains_key(name) => {
+                let expansion = self.call_macro(name, items[1..].to_vec())?;
+                self.expand(&expansion)
+            }
+            _ => {
+                let items = items
+                    .iter()
+                    .map(|item| self.expand(item))
+                    .collect::<Result<_>>()?;
+                Ok(Value::List(items))
+            }
+        }
+    }
+
+    /// Only the unquoted parts of a quasiquote are code.
+    fn expand_template(&mut self, template: &Value, depth: usize) -> Result<Value> {
+        let items = match template {
+            Value::List(items) => items,
+            _ => return Ok(template.clone()),
+        };
+
+        let items = match (head_symbol(items), &items[..]) {
+            (Some("unquote" | "unquote-splicing"), [head, expr]) if depth == 1 => {
+                [head.clone(), self.expand(expr)?].into()
+            }
+            (Some("unquote" | "unquote-splicing"), [head, expr]) => {
+                [head.clone(), self.expand_template(expr, depth - 1)?].into()
+            }
+            (Some("quasiquote"), [head, expr]) => {
+                [head.clone(), self.expand_template(expr, depth + 1)?].into()
+            }
+            _ => items
+                .iter()
+                .map(|item| self.expand_template(item, depth))
+                .collect::<Result<_>>()?,
+        };
+        Ok(Value::List(items))
+    }
+
+    /// `(defmacro name (params) body)`
+    fn define(&mut self, items: &[Value]) -> Result<()> {
+        let [_, name, params, body] = items else {
+            return Err(Error::UnexpectedArgN(3, items.len() - 1).into());
+        };
+        let name = match name {
+            Value::Symbol(name) => name.to_string(),
+            _ => return Err(Error::Expected("identifier").into()),
+        };
+
+        let definition = [
+            Value::Symbol(name.as_str().into()),
+            params.clone(),
+            self.expand(body)?,
+        ];
+        let args = definition
+            .iter()
+            .map(Ast::from_datum)
+            .collect::<Result<Vec<_>>>()?;
+        let function = create_user_function(&args)?;
+
+        self.macros.insert(name, Value::Function(Rc::new(function)));
+        Ok(())
+    }
+
+    fn call_macro(&mut self, name: &str, args: Vec<Value>) -> Result<Value> {
+        let function = self.macros[name].clone();
+        let vm = match &mut self.vm {
+            Some(vm) => vm,
+            None => {
+                let mut vm = Vm::new()?;
+                vm.run(&generate(&std::mem::take(&mut self.functions))?)?;
+                self.vm.insert(vm)
+            }
+        };
+        vm.call(&function, args)
+    }
+}
@@ -0,0 +1,236 @@
+use crate::ast::Cst;
+
+use anyhow::Result;
+
+/// Columns a tab is counted as when checking the line width.
+const TAB_WIDTH: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Indent {
+    Spaces(usize),
+    Tabs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    /// Lists are kept on one line if they fit in this many columns.
+    pub width: usize,
+    pub indent: Indent,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            width: 80,
+            indent: Indent::Spaces(2),
+        }
+    }
+}
+
+/// Pretty-prints `src` in the canonical style, keeping its comments.
+///
+/// A list that fits in the line width stays on one line. Otherwise its head
+/// and the arguments that belong with it (the name and parameters of a `fn`,
+/// or just the first argument of a call) stay on the first line, and the
+/// rest go on lines of their own, one indent deeper, or are packed into as
+/// few lines as possible if they are all atoms. Function and macro
+/// definitions always put their body on a line of its own.
+pub fn format(src: &str, config: &Config) -> Result<String> {
+    let nodes = Cst::parse(src)?;
+    let mut printer = Printer {
+        config,
+        out: String::new(),
+        column: 0,
+    };
+
+    for (i, item) in items(&nodes).iter().enumerate() {
+        match item {
+            _ if i == 0 => {}
+            Item { trailing: true, .. } => printer.write(" "),
+            Item {
+                blank_before: true, ..
+            } => {
+                printer.newline(0);
+                printer.newline(0);
+            }
+            _ => printer.newline(0),
+        }
+        printer.node(item.node, 0);
+    }
+    if !printer.out.is_empty() {
+        printer.newline(0);
+    }
+    Ok(printer.out)
+}
+
+/// An expression or comment among the children of a list.
+struct Item<'c, 'a> {
+    node: &'c Cst<'a>,
+    /// Only kept between top-level forms.
+    blank_before: bool,
+    /// A comment on the same line as the expression before it.
+    trailing: bool,
+}
+
+fn items<'c, 'a>(nodes: &'c [Cst<'a>]) -> Vec<Item<'c, 'a>> {
+    let mut items: Vec<Item> = Vec::new();
+    let mut newlines = 0;
+    for node in nodes {
+        if let Cst::Whitespace(text) = node {
+            newlines += text.matches('\n').count();
+            continue;
+        }
+        let after_expr = items.last().is_some_and(|item| !ends_line(item.node));
+        items.push(Item {
+            node,
+            blank_before: newlines >= 2,
+            trailing: matches!(node, Cst::Comment(_)) && newlines == 0 && after_expr,
+        });
+        newlines = 0;
+    }
+    items
+}
+
+/// Everything on one line, unless it holds a line comment or spans lines.
+fn flat(node: &Cst) -> Option<String> {
+    match node {
+        Cst::Atom(text) | Cst::Comment(text) if text.contains('\n') || ends_line(node) => None,
+        Cst::Atom(text) | Cst::Comment(text) => Some(text.to_string()),
+        Cst::List(children) if is_definition(children) => None,
+        Cst::List(children) => {
+            let mut parts = Vec::new();
+            for child in children {
+                match child {
+                    Cst::Whitespace(_) => {}
+                    node => parts.push(flat(node)?),
+                }
+            }
+            Some(format!("({})", parts.join(" ")))
+        }
+        Cst::Quoted(prefix, inner) => {
+            let parts = inner
+                .iter()
+                .filter(|node| !matches!(node, Cst::Whitespace(_)))
+                .map(flat)
+                .collect::<Option<Vec<_>>>()?;
+            Some(format!("{prefix}{}", parts.join(" ")))
+        }
+        Cst::Whitespace(_) => None,
+    }
+}
+
+fn head<'a>(children: &[Cst<'a>]) -> Option<&'a str> {
+    match children.iter().find(|node| !node.is_trivia()) {
+        Some(Cst::Atom(head)) => Some(head),
+        _ => None,
+    }
+}
+
+/// Only a line comment needs a newline after it, block comments don't.
+fn ends_line(node: &Cst) -> bool {
+    matches!(node, Cst::Comment(text) if text.starts_with(';'))
+}
+
+fn is_definition(children: &[Cst]) -> bool {
+    matches!(head(children), Some("fn" | "defmacro"))
+}
+
+struct Printer<'c> {
+    config: &'c Config,
+    out: String,
+    column: usize,
+}
+
+impl Printer<'_> {
+    fn write(&mut self, text: &str) {
+        self.out.push_str(text);
+        self.column = match text.rfind('\n') {
+            Some(i) => text[i + 1..].chars().count(),
+            None => self.column + text.chars().count(),
+        };
+    }
+
+    fn newline(&mut self, level: usize) {
+        // No trailing whitespace on blank lines
+        while self.out.ends_with([' ', '\t']) {
+            self.out.pop();
+        }
+        self.out.push('\n');
+        let indent = match self.config.indent {
+            Indent::Spaces(n) => " ".repeat(n * level),
+            Indent::Tabs => "\t".repeat(level),
+        };
+        self.out.push_str(&indent);
+        self.column = match self.config.indent {
+            Indent::Spaces(n) => n * level,
+            Indent::Tabs => TAB_WIDTH * level,
+        };
+    }
+
+    fn node(&mut self, node: &Cst, level: usize) {
+        if let Some(text) = flat(node) {
+            if self.column + text.chars().count() <= self.config.width {
+                return self.write(&text);
+            }
+        }
+
+        match node {
+            Cst::List(children) => self.list(children, level),
+            Cst::Quoted(prefix, inner) => {
+                self.write(prefix);
+                let mut previous = None;
+                for node in inner
+                    .iter()
+                    .filter(|node| !matches!(node, Cst::Whitespace(_)))
+                {
+                    match previous {
+                        None => {}
+                        Some(previous) if ends_line(previous) => self.newline(level + 1),
+                        Some(_) => self.write(" "),
+                    }
+                    self.node(node, level);
+                    previous = Some(node);
+                }
+            }
+            Cst::Atom(text) | Cst::Comment(text) => self.write(text),
+            Cst::Whitespace(_) => {}
+        }
+    }
+
+    fn list(&mut self, children: &[Cst], level: usize) {
+        // How many arguments stay on the line of the head
+        let same_line = match head(children) {
+            Some("fn" | "defmacro" | "let") => 3,
+            Some(_) => 2,
+            None => 1,
+        };
+
+        // Lists of plain data are filled rather than given a line per item
+        let fill = children
+            .iter()
+            .all(|node| matches!(node, Cst::Atom(_) | Cst::Whitespace(_)));
+
+        self.write("(");
+        let mut exprs = 0;
+        let mut after_line_comment = false;
+        for (i, item) in items(children).iter().enumerate() {
+            let fits = self.column + 1 + item.node.to_string().chars().count() < self.config.width;
+            match item {
+                _ if i == 0 => {}
+                Item { trailing: true, .. } => self.write(" "),
+                _ if exprs < same_line && !after_line_comment && !item.node.is_trivia() => {
+                    self.write(" ")
+                }
+                _ if fill && fits => self.write(" "),
+                _ => self.newline(level + 1),
+            }
+            self.node(item.node, level + 1);
+            after_line_comment = ends_line(item.node);
+            exprs += usize::from(!item.node.is_trivia());
+        }
+        if after_line_comment {
+            self.newline(level);
+        }
+        self.write(")");
+    }
+}
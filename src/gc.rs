@@ -0,0 +1,247 @@
+//! Bookkeeping for the lists and functions the VM allocates, and a collector
+//! for the cycles among them that reference counting can't free.
+//!
+//! Heap objects are reference counted, so most are freed as soon as the last
+//! value pointing at them goes away. A closure whose upvalues lead back to
+//! itself keeps itself alive, though. [`Heap::collect`] finds such cycles
+//! with a mark-sweep over the objects it tracks. Roots can't be listed, as
+//! anything holding an [`Rc`] can be one, so they are found by subtracting
+//! the references objects hold to each other from their reference counts:
+//! whatever is left over comes from outside. Objects that can't be reached
+//! from those are garbage, and the sweep breaks their cycles by clearing
+//! the upvalues of the functions among them.
+
+use crate::simulator::Function;
+use crate::simulator::Instruction;
+use crate::simulator::Value;
+
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::mem::size_of_val;
+use std::rc::Rc;
+use std::rc::Weak;
+
+/// Bytes to allocate before collecting, whatever the size of the heap.
+const MIN_COLLECTION_BYTES: usize = 1 << 20;
+
+/// Allocation counts of a [`Vm`](crate::simulator::Vm). Sizes are estimates,
+/// counting the values and instructions objects hold but not what those
+/// point to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Objects allocated since the VM was created.
+    pub allocations: usize,
+    pub allocated_bytes: usize,
+    /// Objects not yet known to be freed. Those freed by reference counting
+    /// are noticed at the next collection.
+    pub live_objects: usize,
+    pub live_bytes: usize,
+    pub collections: usize,
+    /// Objects freed by breaking cycles, over all collections.
+    pub collected: usize,
+}
+
+enum Object {
+    List(Weak<[Value]>),
+    Function(Weak<Function>),
+}
+
+/// An object upgraded for the length of a collection.
+enum Live {
+    List(Rc<[Value]>),
+    Function(Rc<Function>),
+}
+
+struct Entry {
+    object: Object,
+    bytes: usize,
+}
+
+impl Entry {
+    fn upgrade(&self) -> Option<Live> {
+        match &self.object {
+            Object::List(list) => list.upgrade().map(Live::List),
+            Object::Function(func) => func.upgrade().map(Live::Function),
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        match &self.object {
+            Object::List(list) => list.strong_count() > 0,
+            Object::Function(func) => func.strong_count() > 0,
+        }
+    }
+}
+
+impl Live {
+    fn address(&self) -> *const () {
+        match self {
+            Live::List(list) => Rc::as_ptr(list) as *const (),
+            Live::Function(func) => Rc::as_ptr(func) as *const (),
+        }
+    }
+
+    /// References held by everything but the collection.
+    fn references(&self) -> usize {
+        match self {
+            Live::List(list) => Rc::strong_count(list) - 1,
+            Live::Function(func) => Rc::strong_count(func) - 1,
+        }
+    }
+
+    /// Calls `f` with each value the object holds. Upvalues shared with a
+    /// running call are left out, so what they point to counts as reachable
+    /// from outside.
+    fn for_each_child(&self, mut f: impl FnMut(&Value)) {
+        match self {
+            Live::List(items) => items.iter().for_each(f),
+            Live::Function(func) => {
+                if let Function::User { upvalues, .. } = &**func {
+                    let upvalues = upvalues.borrow();
+                    if Rc::strong_count(&upvalues) == 1 {
+                        upvalues.iter().for_each(&mut f);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn address(value: &Value) -> Option<*const ()> {
+    match value {
+        Value::List(list) => Some(Rc::as_ptr(list) as *const ()),
+        Value::Function(func) => Some(Rc::as_ptr(func) as *const ()),
+        _ => None,
+    }
+}
+
+/// The objects a VM has allocated, as weak references, so tracking them
+/// doesn't keep them alive.
+pub(crate) struct Heap {
+    objects: Vec<Entry>,
+    stats: HeapStats,
+    /// `allocated_bytes` to collect at.
+    next_collection: usize,
+}
+
+impl Heap {
+    pub(crate) fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+            stats: HeapStats::default(),
+            next_collection: MIN_COLLECTION_BYTES,
+        }
+    }
+
+    pub(crate) fn stats(&self) -> HeapStats {
+        self.stats
+    }
+
+    pub(crate) fn track_list(&mut self, list: &Rc<[Value]>) {
+        let bytes = size_of::<[usize; 2]>() + size_of_val(&**list);
+        self.track(Object::List(Rc::downgrade(list)), bytes);
+    }
+
+    pub(crate) fn track_function(&mut self, func: &Rc<Function>) {
+        let mut bytes = size_of::<[usize; 2]>() + size_of::<Function>();
+        if let Function::User {
+            upvalues, bytecode, ..
+        } = &**func
+        {
+            bytes += size_of_val(&**upvalues.borrow());
+            bytes += bytecode.len() * size_of::<Instruction>();
+        }
+        self.track(Object::Function(Rc::downgrade(func)), bytes);
+    }
+
+    fn track(&mut self, object: Object, bytes: usize) {
+        self.objects.push(Entry { object, bytes });
+        self.stats.allocations += 1;
+        self.stats.allocated_bytes += bytes;
+        self.stats.live_objects += 1;
+        self.stats.live_bytes += bytes;
+    }
+
+    /// Whether enough has been allocated since the last collection to run
+    /// another. The heap may grow by its own size, or a minimum, in between.
+    pub(crate) fn wants_collection(&self) -> bool {
+        self.stats.allocated_bytes >= self.next_collection
+    }
+
+    /// Frees unreachable cycles, returning how many objects were in them.
+    pub(crate) fn collect(&mut self) -> usize {
+        self.forget_freed();
+        let live = self
+            .objects
+            .iter()
+            .filter_map(Entry::upgrade)
+            .collect::<Vec<_>>();
+        let index = live
+            .iter()
+            .enumerate()
+            .map(|(i, object)| (object.address(), i))
+            .collect::<HashMap<_, _>>();
+
+        // What's left once references between tracked objects are taken
+        // away comes from registers, globals, constants or natives.
+        let mut external = live.iter().map(Live::references).collect::<Vec<_>>();
+        for object in &live {
+            object.for_each_child(|child| {
+                if let Some(i) = address(child).and_then(|a| index.get(&a)) {
+                    external[*i] -= 1;
+                }
+            });
+        }
+
+        let mut marked = vec![false; live.len()];
+        let mut pending = (0..live.len())
+            .filter(|i| external[*i] > 0)
+            .collect::<Vec<_>>();
+        while let Some(i) = pending.pop() {
+            if std::mem::replace(&mut marked[i], true) {
+                continue;
+            }
+            live[i].for_each_child(|child| {
+                if let Some(j) = address(child).and_then(|a| index.get(&a)) {
+                    pending.push(*j);
+                }
+            });
+        }
+
+        let mut collected = 0;
+        for (object, marked) in live.iter().zip(&marked) {
+            if *marked {
+                continue;
+            }
+            collected += 1;
+            // Every cycle goes through a function, as lists can't change
+            // after they are made.
+            if let Live::Function(func) = object {
+                if let Function::User { upvalues, .. } = &**func {
+                    upvalues.replace(Rc::new([]));
+                }
+            }
+        }
+        drop(live);
+        self.forget_freed();
+
+        self.stats.collections += 1;
+        self.stats.collected += collected;
+        self.next_collection =
+            self.stats.allocated_bytes + self.stats.live_bytes.max(MIN_COLLECTION_BYTES);
+        collected
+    }
+
+    /// Drops the entries of objects that have been freed.
+    fn forget_freed(&mut self) {
+        let stats = &mut self.stats;
+        self.objects.retain(|entry| {
+            let alive = entry.is_alive();
+            if !alive {
+                stats.live_objects -= 1;
+                stats.live_bytes -= entry.bytes;
+            }
+            alive
+        });
+    }
+}
@@ -0,0 +1,58 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// An interned identifier. Names are interned once, when code is compiled,
+/// so the VM can find functions and variables by indexing instead of hashing
+/// strings. IDs are dense and start at 0, and only mean something on the
+/// thread that interned them.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SymbolId(u32);
+
+#[derive(Default)]
+struct Interner {
+    names: Vec<Rc<str>>,
+    ids: HashMap<Rc<str>, SymbolId>,
+}
+
+thread_local! {
+    static INTERNER: RefCell<Interner> = RefCell::default();
+}
+
+impl SymbolId {
+    /// The ID of `name`, the same for every call with an equal name.
+    pub fn intern(name: &str) -> Self {
+        INTERNER.with_borrow_mut(|interner| {
+            if let Some(id) = interner.ids.get(name) {
+                return *id;
+            }
+            let id = SymbolId(interner.names.len() as u32);
+            let name: Rc<str> = name.into();
+            interner.names.push(name.clone());
+            interner.ids.insert(name, id);
+            id
+        })
+    }
+
+    pub fn name(self) -> Rc<str> {
+        INTERNER.with_borrow(|interner| interner.names[self.index()].clone())
+    }
+
+    /// Position of the symbol's slot in tables indexed by symbol.
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl std::fmt::Display for SymbolId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Shows the name, which is what matters when reading bytecode dumps.
+impl std::fmt::Debug for SymbolId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.name())
+    }
+}
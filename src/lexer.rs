@@ -1,34 +1,168 @@
+use crate::Diagnostic;
 use crate::Error;
 use anyhow::Result;
+use std::borrow::Cow;
+use std::io::BufRead;
+use unicode_normalization::is_nfc;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::MixedScript;
 
-#[derive(Debug, PartialEq)]
+/// ASCII punctuation allowed in identifiers besides letters and digits, so
+/// that names like `->`, `empty?`, `set!`, `<=` and `ns/name` are symbols.
+pub const IDENT_PUNCTUATION: &str = "!$%&*+-./:<=>?@^_~#";
+
+/// Whether `c` can start an identifier: a character with the Unicode
+/// `XID_Start` property, an ASCII digit, or one of [`IDENT_PUNCTUATION`].
+/// Tokens made of these that parse as numbers are numbers instead.
+pub fn is_ident_start(c: char) -> bool {
+    unicode_ident::is_xid_start(c) || c.is_ascii_digit() || IDENT_PUNCTUATION.contains(c)
+}
+
+/// Whether `c` can continue an identifier: a character with the Unicode
+/// `XID_Continue` property, which adds digits and combining marks to
+/// `XID_Start`, or one of [`IDENT_PUNCTUATION`].
+pub fn is_ident_continue(c: char) -> bool {
+    unicode_ident::is_xid_continue(c) || IDENT_PUNCTUATION.contains(c)
+}
+
+/// Identifiers are compared in Normalization Form C, so names that only
+/// differ in how their accents are encoded are the same name.
+pub fn normalize_ident(ident: &str) -> Cow<'_, str> {
+    match is_nfc(ident) {
+        true => Cow::Borrowed(ident),
+        false => Cow::Owned(ident.nfc().collect()),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum Symbol<'a> {
     Ident(&'a str),
     Number(i32),
     StringLiteral(&'a str),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Token<'a> {
     Open,
     Close,
+    Quote,
+    Quasiquote,
+    Unquote,
+    UnquoteSplicing,
     Symbol(Symbol<'a>),
+    /// `#;`, which comments out the expression after it.
+    DatumComment,
+    /// Only produced by [`lex_lossless`].
+    Whitespace(&'a str),
+    /// Only produced by [`lex_lossless`]. A line comment runs up to, but not
+    /// including, the newline, a block comment includes its delimiters.
+    Comment(&'a str),
+}
+
+/// A [`Token`] that owns its text, as produced by [`StreamLexer`].
+#[derive(Debug, PartialEq, Clone)]
+pub enum OwnedToken {
+    Open,
+    Close,
+    Quote,
+    Quasiquote,
+    Unquote,
+    UnquoteSplicing,
+    Symbol(OwnedSymbol),
+    DatumComment,
+    Whitespace(String),
+    Comment(String),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum OwnedSymbol {
+    Ident(String),
+    Number(i32),
+    StringLiteral(String),
+}
+
+impl From<Token<'_>> for OwnedToken {
+    fn from(tok: Token) -> Self {
+        match tok {
+            Token::Open => OwnedToken::Open,
+            Token::Close => OwnedToken::Close,
+            Token::Quote => OwnedToken::Quote,
+            Token::Quasiquote => OwnedToken::Quasiquote,
+            Token::Unquote => OwnedToken::Unquote,
+            Token::UnquoteSplicing => OwnedToken::UnquoteSplicing,
+            Token::Symbol(Symbol::Ident(ident)) => {
+                OwnedToken::Symbol(OwnedSymbol::Ident(ident.into()))
+            }
+            Token::Symbol(Symbol::Number(n)) => OwnedToken::Symbol(OwnedSymbol::Number(n)),
+            Token::Symbol(Symbol::StringLiteral(s)) => {
+                OwnedToken::Symbol(OwnedSymbol::StringLiteral(s.into()))
+            }
+            Token::DatumComment => OwnedToken::DatumComment,
+            Token::Whitespace(text) => OwnedToken::Whitespace(text.into()),
+            Token::Comment(text) => OwnedToken::Comment(text.into()),
+        }
+    }
+}
+
+impl OwnedToken {
+    /// Borrows the token back, to hand it to the parser.
+    pub fn as_token(&self) -> Token<'_> {
+        match self {
+            OwnedToken::Open => Token::Open,
+            OwnedToken::Close => Token::Close,
+            OwnedToken::Quote => Token::Quote,
+            OwnedToken::Quasiquote => Token::Quasiquote,
+            OwnedToken::Unquote => Token::Unquote,
+            OwnedToken::UnquoteSplicing => Token::UnquoteSplicing,
+            OwnedToken::Symbol(OwnedSymbol::Ident(ident)) => Token::Symbol(Symbol::Ident(ident)),
+            OwnedToken::Symbol(OwnedSymbol::Number(n)) => Token::Symbol(Symbol::Number(*n)),
+            OwnedToken::Symbol(OwnedSymbol::StringLiteral(s)) => {
+                Token::Symbol(Symbol::StringLiteral(s))
+            }
+            OwnedToken::DatumComment => Token::DatumComment,
+            OwnedToken::Whitespace(text) => Token::Whitespace(text),
+            OwnedToken::Comment(text) => Token::Comment(text),
+        }
+    }
+}
+
+/// Byte range of a token in the source it was lexed from.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
 }
 
 struct Lexer<'a> {
     src: &'a str,
-    line: usize,
-    column: usize,
+    /// Length of the whole input, to turn what is left of it into offsets.
+    len: usize,
+    /// Offset of the token being lexed.
+    start: usize,
+    /// Emit whitespace and comments as tokens instead of skipping them.
+    lossless: bool,
 }
 
 impl<'a> Lexer<'a> {
     fn new(src: &'a str) -> Self {
         Self {
-            src: src.trim_end(),
-            line: 0,
-            column: 0,
+            src,
+            len: src.len(),
+            start: 0,
+            lossless: false,
         }
     }
+
+    fn lossless(src: &'a str) -> Self {
+        Self {
+            lossless: true,
+            ..Self::new(src)
+        }
+    }
+
+    fn offset(&self) -> usize {
+        self.len - self.src.len()
+    }
 }
 
 // Need to support
@@ -36,89 +170,318 @@ impl<'a> Lexer<'a> {
 // 2. Identifiers
 // 3. Number literals
 // 4. String literals (!)
+// 5. Quote prefixes: ' ` , ,@
+// 6. Comments: ; #| |# #;
+
+impl<'a> Lexer<'a> {
+    /// Byte offset of the first character after the current one for which
+    /// `when` holds.
+    fn end<F: Fn(char) -> bool>(&self, when: F) -> Option<usize> {
+        self.src
+            .char_indices()
+            .skip(1)
+            .find(|&(_, c)| when(c))
+            .map(|(i, _)| i)
+    }
+
+    /// Like [`Lexer::end`], but running into the end of the input is fine.
+    fn end_or_eof<F: Fn(char) -> bool>(&self, when: F) -> usize {
+        self.end(when).unwrap_or(self.src.len())
+    }
 
-impl Lexer<'_> {
-    fn end<F: Fn(char) -> bool>(&mut self, when: F) -> Option<usize> {
-        // for (i, c) in (1..).zip(self.src.chars()) {
-        for (i, c) in self.src.chars().enumerate().skip(1) {
-            match c {
-                '\n' => {
-                    self.line += 1;
-                    self.column = 0;
+    /// Length of the block comment at the start of the input, which may
+    /// contain other block comments.
+    fn block_comment(&self) -> Option<usize> {
+        let bytes = self.src.as_bytes();
+        let mut depth = 0;
+        let mut i = 0;
+        while i + 1 < bytes.len() {
+            match &bytes[i..i + 2] {
+                b"#|" => depth += 1,
+                b"|#" => depth -= 1,
+                _ => {
+                    i += 1;
+                    continue;
                 }
-                _ => self.column += 1,
             }
-
-            if when(c) {
+            i += 2;
+            if depth == 0 {
                 return Some(i);
             }
         }
         None
     }
-}
 
-impl<'a> Iterator for Lexer<'a> {
-    type Item = Result<Token<'a>>;
+    fn take(&mut self, len: usize, token: Token<'a>) -> Token<'a> {
+        self.src = &self.src[len..];
+        token
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
+    fn take_str(&mut self, len: usize, token: fn(&'a str) -> Token<'a>) -> Token<'a> {
+        let text = &self.src[..len];
+        self.take(len, token(text))
+    }
+
+    /// The next token, whitespace and comments included.
+    fn next_token(&mut self) -> Option<Result<Token<'a>, Error>> {
+        self.start = self.offset();
         let first = self.src.chars().next()?;
-        match first {
-            w if w.is_whitespace() => match self.end(|c| !c.is_whitespace()) {
-                Some(len) => {
-                    self.src = &self.src[len..];
-                    self.next()
-                }
-                None => Some(Err(Error::TrailingWhitespace.into())),
-            },
-            ';' => match self.end(|c| c == '\n') {
-                Some(len) => {
-                    self.src = &self.src[len..];
-                    self.next()
-                }
-                None => Some(Err(Error::UndelimitedComment.into())),
-            },
-            '(' => {
-                self.column += 1;
-                self.src = &self.src[1..];
-                Some(Ok(Token::Open))
+        let token = match first {
+            w if w.is_whitespace() => {
+                let len = self.end_or_eof(|c| !c.is_whitespace());
+                self.take_str(len, Token::Whitespace)
             }
-            ')' => {
-                self.column += 1;
-                self.src = &self.src[1..];
-                Some(Ok(Token::Close))
+            ';' => {
+                let len = self.end_or_eof(|c| c == '\n');
+                self.take_str(len, Token::Comment)
             }
+            '#' if self.src[1..].starts_with('|') => match self.block_comment() {
+                Some(len) => self.take_str(len, Token::Comment),
+                None => return Some(Err(Error::UndelimitedComment)),
+            },
+            '#' if self.src[1..].starts_with(';') => self.take(2, Token::DatumComment),
+            '(' => self.take(1, Token::Open),
+            ')' => self.take(1, Token::Close),
+            '\'' => self.take(1, Token::Quote),
+            '`' => self.take(1, Token::Quasiquote),
+            ',' => match self.src[1..].starts_with('@') {
+                true => self.take(2, Token::UnquoteSplicing),
+                false => self.take(1, Token::Unquote),
+            },
             '"' => match self.end(|c| c == '"') {
                 Some(len) => {
                     let literal = Symbol::StringLiteral(&self.src[1..len]);
-                    self.src = &self.src[len + 1..];
-                    Some(Ok(Token::Symbol(literal)))
+                    self.take(len + 1, Token::Symbol(literal))
                 }
-                None => Some(Err(Error::UndelimitedString.into())),
+                None => return Some(Err(Error::UndelimitedString)),
             },
-            _ => match self.end(|c| c.is_whitespace() || c == '(' || c == ')') {
-                Some(len) => {
-                    let sym = &self.src[..len];
-                    self.src = &self.src[len..];
-
-                    Some(Ok(Token::Symbol(match sym.parse::<i32>() {
+            c if is_ident_start(c) => {
+                let len = self.end_or_eof(|c| !is_ident_continue(c));
+                let sym = &self.src[..len];
+                self.take(
+                    len,
+                    Token::Symbol(match sym.parse::<i32>() {
                         Ok(num) => Symbol::Number(num),
                         _ => Symbol::Ident(sym),
-                    })))
-                }
-                None => Some(Err(Error::Expected("delimited symbol?").into())),
-            },
+                    }),
+                )
+            }
+            c => return Some(Err(Error::UnexpectedChar(c))),
+        };
+        Some(Ok(token))
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<Token<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_token()? {
+                Ok(Token::Whitespace(_) | Token::Comment(_)) if !self.lossless => continue,
+                result => return Some(result),
+            }
         }
     }
 }
 
-pub fn lex(src: &str) -> Result<Vec<Token>> {
+pub fn lex(src: &str) -> Result<Vec<Token<'_>>> {
     let lexer = Lexer::new(src);
     let mut tokens = Vec::new();
     for maybe in lexer {
         match maybe {
             Ok(tok) => tokens.push(tok),
-            Err(err) => return Err(err),
+            Err(err) => return Err(err.into()),
         }
     }
     Ok(tokens)
 }
+
+/// Lexes every byte of `src` into a token, including whitespace and comments,
+/// so the spans cover the whole input without gaps.
+pub fn lex_lossless(src: &str) -> Result<Vec<(Token<'_>, Span)>> {
+    let mut lexer = Lexer::lossless(src);
+    let mut tokens = Vec::new();
+    while let Some(maybe) = lexer.next() {
+        let span = Span {
+            start: lexer.start,
+            end: lexer.offset(),
+        };
+        tokens.push((maybe?, span));
+    }
+    Ok(tokens)
+}
+
+/// Like [`lex`], but keeps each token's span. Errors are reported as
+/// diagnostics instead: an unexpected character is skipped, and lexing goes
+/// on after it, while an unterminated string or comment ends the input.
+pub fn lex_spanned(src: &str) -> (Vec<(Token<'_>, Span)>, Vec<Diagnostic>) {
+    let mut lexer = Lexer::new(src);
+    let mut tokens = Vec::new();
+    let mut diagnostics = Vec::new();
+    while let Some(maybe) = lexer.next() {
+        match maybe {
+            Ok(tok) => tokens.push((
+                tok,
+                Span {
+                    start: lexer.start,
+                    end: lexer.offset(),
+                },
+            )),
+            Err(Error::UnexpectedChar(c)) => {
+                let span = Span {
+                    start: lexer.start,
+                    end: lexer.start + c.len_utf8(),
+                };
+                diagnostics.push(Diagnostic::new(src, span, Error::UnexpectedChar(c)));
+                lexer.src = &lexer.src[c.len_utf8()..];
+            }
+            Err(err) => {
+                // Unterminated strings and comments run on to the end of the input
+                let span = Span {
+                    start: lexer.start,
+                    end: src.len(),
+                };
+                diagnostics.push(Diagnostic::new(src, span, err));
+                break;
+            }
+        }
+    }
+    (tokens, diagnostics)
+}
+
+/// Warns about identifiers mixing scripts, like a Latin name with a Cyrillic
+/// `а` in it, which can look identical to a different name.
+pub fn mixed_script_warnings(src: &str) -> Vec<Diagnostic> {
+    let (tokens, _) = lex_spanned(src);
+    tokens
+        .into_iter()
+        .filter_map(|(tok, span)| match tok {
+            Token::Symbol(Symbol::Ident(ident)) if !ident.is_single_script() => Some(
+                Diagnostic::new(src, span, Error::MixedScript(ident.to_string())),
+            ),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Lexes source read incrementally from `reader`, so it never has to be in
+/// memory all at once. Only the token being lexed is buffered: a symbol,
+/// whitespace or line comment that reaches the end of what has been read so
+/// far may continue in the next chunk, so it is finished only once more
+/// input or the end of it arrives.
+pub struct StreamLexer<R> {
+    reader: R,
+    /// Source read but not yet lexed starts at `pos`.
+    buf: String,
+    pos: usize,
+    /// Offset of `buf` in the whole input.
+    offset: usize,
+    /// The end of a chunk can split a character, which waits here.
+    partial: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: BufRead> StreamLexer<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buf: String::new(),
+            pos: 0,
+            offset: 0,
+            partial: Vec::new(),
+            eof: false,
+        }
+    }
+
+    /// Appends the next chunk of input to the buffer.
+    fn fill(&mut self) -> Result<()> {
+        let chunk = self.reader.fill_buf()?;
+        if chunk.is_empty() {
+            self.eof = true;
+            return match self.partial.is_empty() {
+                true => Ok(()),
+                false => Err(std::str::from_utf8(&self.partial).unwrap_err().into()),
+            };
+        }
+        self.partial.extend_from_slice(chunk);
+        let len = chunk.len();
+        self.reader.consume(len);
+
+        // Drop what was already lexed before growing the buffer
+        self.buf.drain(..self.pos);
+        self.offset += self.pos;
+        self.pos = 0;
+
+        let valid = match std::str::from_utf8(&self.partial) {
+            Ok(text) => text.len(),
+            Err(err) if err.error_len().is_none() => err.valid_up_to(),
+            Err(err) => return Err(err.into()),
+        };
+        let text = std::str::from_utf8(&self.partial[..valid]).expect("checked above");
+        self.buf.push_str(text);
+        self.partial.drain(..valid);
+        Ok(())
+    }
+
+    /// The next token, whitespace and comments included.
+    fn next_token(&mut self) -> Option<Result<(OwnedToken, Span)>> {
+        loop {
+            let rest = &self.buf[self.pos..];
+            let mut lexer = Lexer::lossless(rest);
+            let lexed = lexer.next_token();
+            let len = lexer.offset();
+
+            // A token that could go on past the end of the buffer has to wait
+            let extendable = |tok: &Token| match tok {
+                Token::Whitespace(_) | Token::Unquote | Token::Symbol(Symbol::Ident(_)) => true,
+                Token::Symbol(Symbol::Number(_)) => true,
+                Token::Comment(text) => text.starts_with(';'),
+                _ => false,
+            };
+            match lexed {
+                None if !self.eof => {}
+                Some(Ok(ref tok)) if !self.eof && len == rest.len() && extendable(tok) => {}
+                Some(Err(Error::UndelimitedString | Error::UndelimitedComment)) if !self.eof => {}
+                None => return None,
+                Some(Ok(tok)) => {
+                    let tok = OwnedToken::from(tok);
+                    let start = self.offset + self.pos;
+                    self.pos += len;
+                    let span = Span {
+                        start,
+                        end: start + len,
+                    };
+                    return Some(Ok((tok, span)));
+                }
+                Some(Err(err)) => {
+                    // Skip the offending character, or everything if the
+                    // error runs to the end of the input
+                    self.pos = match err {
+                        Error::UnexpectedChar(c) => self.pos + c.len_utf8(),
+                        _ => self.buf.len(),
+                    };
+                    return Some(Err(err.into()));
+                }
+            }
+
+            if let Err(err) = self.fill() {
+                self.partial.clear();
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for StreamLexer<R> {
+    type Item = Result<(OwnedToken, Span)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_token()? {
+                Ok((OwnedToken::Whitespace(_) | OwnedToken::Comment(_), _)) => continue,
+                result => return Some(result),
+            }
+        }
+    }
+}
@@ -1,5 +1,15 @@
+pub mod asm;
 pub mod ast;
+mod builtins;
+pub mod bytecode;
+pub mod expander;
+pub mod format;
+pub mod gc;
+pub mod intern;
 pub mod lexer;
+pub mod modules;
+pub mod optimize;
+pub mod resolver;
 pub mod simulator;
 
 #[derive(thiserror::Error, Debug)]
@@ -10,24 +20,110 @@ pub enum Error {
     Expected(&'static str),
     #[error("Unknown function: {0}")]
     UnknownFunction(String),
+    #[error("Unknown variable: {0}")]
+    UnknownVariable(String),
+    #[error("Not callable: {0}")]
+    NotCallable(String),
     #[error("Unimplemented: {0}")]
     Unimplemented(&'static str),
     #[error("Code generation failed")]
     CodegenFailed,
-    #[error("Trailing whitespace")]
-    TrailingWhitespace,
+    #[error("Unexpected character {0:?}")]
+    UnexpectedChar(char),
+    #[error("Mixed-script identifier: {0}")]
+    MixedScript(String),
     #[error("Undelimited comment")]
     UndelimitedComment,
     #[error("Undelimited string")]
     UndelimitedString,
     #[error("Unmatched '('")]
     UnmatchedOpenExpr,
+    #[error("Unmatched ')'")]
+    UnmatchedCloseExpr,
+    #[error("Module not found: {0}")]
+    ModuleNotFound(String),
+    #[error("Import cycle: {0}")]
+    ImportCycle(String),
+    #[error("Name clash: {0}")]
+    NameClash(String),
+    #[error("Invalid bytecode: {0}")]
+    InvalidBytecode(&'static str),
+    #[error("Unsupported bytecode version {0}")]
+    BytecodeVersion(u16),
+    #[error("Unknown instruction: {0}")]
+    UnknownInstruction(String),
+    #[error("Unknown option: {0}")]
+    UnknownOption(String),
+    #[error("Division by zero")]
+    DivisionByZero,
+    #[error("Instruction limit of {0} reached")]
+    InstructionLimit(u64),
+    #[error("Call depth limit of {0} reached")]
+    CallDepthLimit(usize),
+    #[error("Natives calling back into the VM nested over {0} deep")]
+    NestedRunLimit(usize),
+    #[error("Register limit of {0} reached")]
+    RegisterLimit(usize),
+    #[error("Heap limit of {0} bytes reached")]
+    HeapLimit(usize),
+    #[error("Interrupted")]
+    Interrupted,
+}
+
+/// An error tied to the part of the source it is about.
+#[derive(Debug)]
+pub struct Diagnostic {
+    /// 1-based.
+    pub line: usize,
+    /// 1-based, counted in characters.
+    pub column: usize,
+    pub span: lexer::Span,
+    pub error: Error,
+}
+
+impl Diagnostic {
+    pub fn new(src: &str, span: lexer::Span, error: Error) -> Self {
+        let before = src.get(..span.start).unwrap_or(src);
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Self {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            span,
+            error,
+        }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.error)
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::asm::*;
     use crate::ast::*;
+    use crate::bytecode::*;
+    use crate::expander::*;
+    use crate::format::*;
+    use crate::intern::*;
     use crate::lexer::*;
+    use crate::modules::*;
+    use crate::optimize::*;
+    use crate::resolver::*;
+    use crate::simulator::*;
+    use crate::Error;
+    use proptest::prelude::*;
+    use std::cell::Cell;
+    use std::io::BufReader;
+    use std::io::Read;
+    use std::path::Path;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+    use std::sync::atomic;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
     use Symbol as S;
     use Token as T;
 
@@ -84,6 +180,24 @@ mod tests {
         assert_eq!(lex(sample).expect("Lexing failed!"), expected);
     }
 
+    #[test]
+    fn lexer_quote_prefixes() {
+        let sample = "'a `(b ,c ,@d)";
+        let expected = &[
+            T::Quote,
+            T::Symbol(S::Ident("a")),
+            T::Quasiquote,
+            T::Open,
+            T::Symbol(S::Ident("b")),
+            T::Unquote,
+            T::Symbol(S::Ident("c")),
+            T::UnquoteSplicing,
+            T::Symbol(S::Ident("d")),
+            T::Close,
+        ];
+        assert_eq!(lex(sample).expect("Lexing failed!"), expected);
+    }
+
     #[test]
     fn ast_basic() {
         let sample = &[
@@ -142,4 +256,1744 @@ mod tests {
         ])]);
         assert_eq!(Tree::try_construct(sample).unwrap(), expected);
     }
+
+    #[test]
+    fn ast_quote_desugars() {
+        let sample = &[
+            T::Open,
+            T::Symbol(S::Ident("f")),
+            T::Quote,
+            T::Open,
+            T::Symbol(S::Number(1)),
+            T::Symbol(S::Number(2)),
+            T::Close,
+            T::Close,
+        ];
+        let expected = Tree::Branch(vec![Tree::Branch(vec![
+            Tree::Leaf(&S::Ident("f")),
+            Tree::Branch(vec![
+                Tree::Leaf(&S::Ident("quote")),
+                Tree::Branch(vec![Tree::Leaf(&S::Number(1)), Tree::Leaf(&S::Number(2))]),
+            ]),
+        ])]);
+        assert_eq!(Tree::try_construct(sample).unwrap(), expected);
+    }
+
+    #[test]
+    fn ast_empty_and_single_element_lists() {
+        let sample = &[
+            T::Open,
+            T::Close,
+            T::Open,
+            T::Symbol(S::Ident("x")),
+            T::Close,
+            T::Symbol(S::Ident("y")),
+        ];
+        let expected = Tree::Branch(vec![
+            Tree::Branch(vec![]),
+            Tree::Branch(vec![Tree::Leaf(&S::Ident("x"))]),
+            Tree::Leaf(&S::Ident("y")),
+        ]);
+        assert_eq!(Tree::try_construct(sample).unwrap(), expected);
+
+        let sample = &[T::Symbol(S::Number(5))];
+        let expected = Tree::Branch(vec![Tree::Leaf(&S::Number(5))]);
+        assert_eq!(Tree::try_construct(sample).unwrap(), expected);
+        assert_eq!(Tree::try_construct(&[]).unwrap(), Tree::Branch(vec![]));
+    }
+
+    #[test]
+    fn ast_rejects_unbalanced_input() {
+        assert!(Tree::try_construct(&[T::Open, T::Open, T::Close]).is_err());
+        assert!(Tree::try_construct(&[T::Open, T::Close, T::Close]).is_err());
+        assert!(Tree::try_construct(&[T::Open, T::Quote, T::Close]).is_err());
+    }
+
+    #[test]
+    fn ast_deep_nesting() {
+        let depth = 10_000;
+        let mut sample = vec![T::Open; depth];
+        sample.extend((0..depth).map(|_| T::Close));
+        let mut tree = Tree::try_construct(&sample).unwrap();
+        for _ in 0..depth {
+            tree = match tree {
+                Tree::Branch(mut children) if children.len() == 1 => children.pop().unwrap(),
+                _ => panic!("Expected a single child"),
+            };
+        }
+        assert_eq!(tree, Tree::Branch(vec![]));
+    }
+
+    /// The slicing parser `Tree::try_construct` replaced, kept as an oracle.
+    mod legacy {
+        use crate::ast::Tree;
+        use crate::lexer::Symbol;
+        use crate::lexer::Token;
+        use crate::Error;
+        use anyhow::Result;
+
+        static QUOTE: Symbol<'static> = Symbol::Ident("quote");
+
+        fn prefix_symbol(tok: &Token) -> Option<&'static Symbol<'static>> {
+            match tok {
+                Token::Quote => Some(&QUOTE),
+                _ => None,
+            }
+        }
+
+        fn take_expr<'a>(toks: &'a [Token]) -> Result<(&'a [Token<'a>], &'a [Token<'a>])> {
+            match toks[0] {
+                Token::Symbol(_) => Ok((&toks[0..1], &toks[1..])),
+                Token::Open => {
+                    let mut scope = 0;
+                    for (i, t) in toks.iter().enumerate() {
+                        match t {
+                            Token::Open => scope += 1,
+                            Token::Close => scope -= 1,
+                            _ => {}
+                        }
+                        if scope == 0 {
+                            return Ok((&toks[0..i + 1], &toks[i + 1..]));
+                        }
+                    }
+                    Err(Error::UnmatchedOpenExpr.into())
+                }
+                Token::Quote | Token::Quasiquote | Token::Unquote | Token::UnquoteSplicing => {
+                    let quoted = toks.get(1..).filter(|rest| !rest.is_empty());
+                    let (expr, _) =
+                        take_expr(quoted.ok_or(Error::Expected("expression after quote"))?)?;
+                    let len = expr.len() + 1;
+                    Ok((&toks[0..len], &toks[len..]))
+                }
+                _ => Err(Error::Expected("( or symbol)").into()),
+            }
+        }
+
+        fn take_toplevel_exprs<'a>(toks: &'a [Token]) -> Result<Vec<&'a [Token<'a>]>> {
+            if toks.is_empty() {
+                return Err(Error::Expected("nonempty list").into());
+            }
+
+            let mut exprs = Vec::new();
+            let mut tail = toks;
+            while !tail.is_empty() {
+                let head;
+                (head, tail) = take_expr(tail)?;
+                exprs.push(head);
+            }
+            Ok(exprs)
+        }
+
+        fn slice_middle<T>(slc: &[T]) -> Option<&[T]> {
+            if slc.len() < 3 {
+                return None;
+            }
+            Some(&slc[1..slc.len() - 1])
+        }
+
+        fn construct_expr<'a>(expr: &'a [Token]) -> Result<Tree<'a>> {
+            if let Some(sym) = prefix_symbol(&expr[0]) {
+                let quoted = construct_expr(&expr[1..])?;
+                return Ok(Tree::Branch(vec![Tree::Leaf(sym), quoted]));
+            }
+            match slice_middle(expr) {
+                Some(middle) => try_construct(middle),
+                None => try_construct(expr),
+            }
+        }
+
+        pub fn try_construct<'a>(toks: &'a [Token]) -> Result<Tree<'a>> {
+            match toks {
+                [Token::Symbol(sym)] => Ok(Tree::Leaf(sym)),
+                _ => {
+                    let expressions = take_toplevel_exprs(toks)?;
+                    let tree: Result<Vec<_>, _> =
+                        expressions.into_iter().map(construct_expr).collect();
+                    Ok(Tree::Branch(tree?))
+                }
+            }
+        }
+    }
+
+    /// Token trees the legacy parser handled correctly: no top-level lone
+    /// leaf, and no lists with fewer than two elements.
+    fn arb_expr() -> impl Strategy<Value = Vec<Token<'static>>> {
+        let leaf = prop_oneof![
+            any::<i32>().prop_map(|n| vec![T::Symbol(S::Number(n))]),
+            prop::sample::select(vec!["a", "+", "foo", "empty?"])
+                .prop_map(|ident| vec![T::Symbol(S::Ident(ident))]),
+        ];
+        leaf.prop_recursive(6, 64, 6, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 2..6).prop_map(|children| {
+                    let mut toks = vec![T::Open];
+                    toks.extend(children.into_iter().flatten());
+                    toks.push(T::Close);
+                    toks
+                }),
+                inner.prop_map(|quoted| {
+                    let mut toks = vec![T::Quote];
+                    toks.extend(quoted);
+                    toks
+                }),
+            ]
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn ast_matches_legacy_parser(forms in prop::collection::vec(arb_expr(), 2..5)) {
+            let toks = forms.into_iter().flatten().collect::<Vec<_>>();
+            prop_assert_eq!(
+                Tree::try_construct(&toks).unwrap(),
+                legacy::try_construct(&toks).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn ast_check_reports_every_error() {
+        let sample = "(echo (+ 1 2)\n(echo 3))\n(echo 'x)\n(echo \"oops";
+        let found = check(sample)
+            .iter()
+            .map(|d| (d.line, d.column, d.error.to_string()))
+            .collect::<Vec<_>>();
+        let expected = [
+            (1, 1, Error::UnmatchedOpenExpr),
+            (2, 9, Error::UnmatchedCloseExpr),
+            (4, 1, Error::UnmatchedOpenExpr),
+            (4, 7, Error::UndelimitedString),
+        ]
+        .map(|(line, column, error)| (line, column, error.to_string()));
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn ast_check_goes_on_after_unexpected_characters() {
+        let sample = "(echo [1])\n(echo {2})\n(echo (+ 1 2)\n";
+        let found = check(sample)
+            .iter()
+            .map(|d| (d.line, d.column, d.error.to_string()))
+            .collect::<Vec<_>>();
+        let expected = [
+            (1, 7, Error::UnexpectedChar('[')),
+            (1, 9, Error::UnexpectedChar(']')),
+            (2, 7, Error::UnexpectedChar('{')),
+            (2, 9, Error::UnexpectedChar('}')),
+            (3, 1, Error::UnmatchedOpenExpr),
+        ]
+        .map(|(line, column, error)| (line, column, error.to_string()));
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn ast_check_accepts_oddly_laid_out_code() {
+        assert!(check("(fn f x\n(+ x 1))\n(f 2)").is_empty());
+        assert_eq!(check("'\n)").len(), 2);
+    }
+
+    fn eval(src: &str) -> Value {
+        Vm::new()
+            .expect("Prelude failed!")
+            .eval(src)
+            .expect("Evaluation failed!")
+    }
+
+    fn eval_display(src: &str) -> String {
+        eval(src).to_string()
+    }
+
+    #[test]
+    fn vm_user_function_args_in_order() {
+        assert_eq!(
+            eval("(fn sub (a b) (- a b)) (sub 10 3)"),
+            Value::Signed32(7)
+        );
+    }
+
+    #[test]
+    fn vm_zero_and_one_argument_calls() {
+        assert_eq!(eval("(fn answer () 42) (answer)"), Value::Signed32(42));
+        assert_eq!(eval("(fn id (x) x) (id 7)"), Value::Signed32(7));
+        assert_eq!(eval_display("(list)"), "()");
+    }
+
+    #[test]
+    fn vm_if() {
+        assert_eq!(eval("(if (< 1 2) 10 20)"), Value::Signed32(10));
+        assert_eq!(eval("(if (> 1 2) 10 20)"), Value::Signed32(20));
+        assert_eq!(eval("(if false 10)"), Value::Nil);
+    }
+
+    #[test]
+    fn arithmetic_is_n_ary() {
+        let table = [
+            ("(+)", Ok("0")),
+            ("(*)", Ok("1")),
+            ("(-)", Err("Expected at least one operand")),
+            ("(/)", Err("Expected at least one operand")),
+            ("(+ 5)", Ok("5")),
+            ("(* 5)", Ok("5")),
+            ("(- 5)", Ok("-5")),
+            ("(- -5)", Ok("5")),
+            ("(/ 1)", Ok("1")),
+            ("(/ -1)", Ok("-1")),
+            ("(/ 2)", Ok("0")),
+            ("(/ 0)", Err("Division by zero")),
+            ("(+ 1 2 3 4)", Ok("10")),
+            ("(* 2 3 4)", Ok("24")),
+            ("(- 10 3)", Ok("7")),
+            ("(- 10 3 2)", Ok("5")),
+            ("(/ 100 5 2)", Ok("10")),
+            ("(/ 7 2)", Ok("3")),
+            ("(/ -7 2)", Ok("-3")),
+            ("(/ 5 0)", Err("Division by zero")),
+            ("(/ 5 1 0)", Err("Division by zero")),
+            ("(+ 2147483647 1)", Ok("-2147483648")),
+            ("(- -2147483648)", Ok("-2147483648")),
+            ("(/ -2147483648 -1)", Ok("-2147483648")),
+            ("(+ 'a)", Err("Expected number")),
+            ("(- 1 \"a\")", Err("Expected number")),
+            ("(* 2 nil 3)", Err("Expected number")),
+        ];
+
+        let run = |bytecode: Vec<Instruction>| {
+            let value = Vm::new().unwrap().eval_bytecode(&bytecode);
+            value.map(|v| v.to_string()).map_err(|err| err.to_string())
+        };
+        for (src, expected) in table {
+            let expected = expected.map(String::from).map_err(String::from);
+            assert_eq!(run(compile(src).unwrap()), expected, "{src}");
+            // The builtin, and the operation folded at compile time, agree
+            let inner = &src[1..src.len() - 1];
+            let (op, args) = inner.split_once(' ').unwrap_or((inner, ""));
+            let apply = format!("(apply {op} (list {args}))");
+            assert_eq!(run(compile(&apply).unwrap()), expected, "{apply}");
+            let optimized = compile_optimized(src, &Options::new(2)).unwrap();
+            assert_eq!(run(optimized), expected, "{src} at -O2");
+        }
+    }
+
+    #[test]
+    fn range_and_fold() {
+        assert_eq!(eval_display("(range 0 5)"), "(0 1 2 3 4)");
+        assert_eq!(
+            eval_display("(list (range 5 5) (range 5 0) (range -2 1))"),
+            "(nil nil (-2 -1 0))"
+        );
+        assert_eq!(eval("(fold + 0 (range 1 5))"), Value::Signed32(10));
+
+        // Large ranges are built in one go, not by recursion
+        let sample = "(let xs (range 0 1000000) (list (length xs) (first xs) (first (rest xs))))";
+        assert_eq!(eval_display(sample), "(1000000 0 1)");
+        assert_eq!(
+            eval("(reduce + 0 (range 0 10000))"),
+            Value::Signed32(49995000)
+        );
+
+        let mut vm = Vm::new().unwrap();
+        assert!(vm.eval("(range 0 'a)").is_err());
+        vm.set_limits(Limits {
+            heap_bytes: Some(vm.heap_stats().live_bytes + 100_000),
+            ..Limits::default()
+        });
+        assert!(vm.eval("(length (range 0 1000))").is_ok());
+        let err = vm.eval("(range -2000000000 2000000000)").unwrap_err();
+        assert!(err.to_string().starts_with("Heap limit of"), "{err}");
+    }
+
+    #[test]
+    fn higher_order_map_filter_reduce() {
+        let sample = "
+            (fn square n (* n n))
+            (fn odd? n (= 1 (- n (* 2 (/ n 2)))))
+            (reduce + 0 (map square (filter odd? (range 0 6))))";
+        assert_eq!(eval(sample), Value::Signed32(1 + 9 + 25));
+    }
+
+    #[test]
+    fn higher_order_apply_and_compose() {
+        assert_eq!(eval("(apply * (list 2 3 4))"), Value::Signed32(24));
+        let sample = "
+            (fn inc n (+ n 1))
+            (fn double n (* n 2))
+            (fn call-with-five f (f 5))
+            (call-with-five (compose inc double))";
+        assert_eq!(eval(sample), Value::Signed32(11));
+    }
+
+    #[test]
+    fn higher_order_sort_by_and_for_each() {
+        let sample = "
+            (fn negate n (- 0 n))
+            (sort-by negate (list 3 1 2))";
+        assert_eq!(eval_display(sample), "(3 2 1)");
+        assert_eq!(eval("(for-each not (list 1 2))"), Value::Nil);
+    }
+
+    #[test]
+    fn quote_produces_data() {
+        assert_eq!(eval("(first (list 'foo))"), Value::Symbol("foo".into()));
+        assert_eq!(eval_display("'(a (b \"c\") 1)"), "(a (b \"c\") 1)");
+        assert_eq!(eval_display("(quote (+ 1 2))"), "(+ 1 2)");
+    }
+
+    #[test]
+    fn quasiquote_unquote_and_splice() {
+        assert_eq!(eval_display("`(a ,(+ 1 2) ,@(list 4 5) b)"), "(a 3 4 5 b)");
+        assert_eq!(eval_display("`(x ,@(range 0 3))"), "(x 0 1 2)");
+        assert_eq!(
+            eval_display("`(a `(b ,(c ,(+ 1 1))))"),
+            "(a (quasiquote (b (unquote (c 2)))))"
+        );
+        assert!(Vm::new().unwrap().eval(",x").is_err());
+    }
+
+    #[test]
+    fn macro_expands_before_codegen() {
+        let sample = "
+            (defmacro unless (c body) `(if ,c nil ,body))
+            (fn safe-div (a b) (unless (= b 0) (/ a b)))
+            (safe-div 10 2)";
+        let forms = Expander::new().expand_all(read(sample).unwrap()).unwrap();
+        let expanded = forms.iter().map(Value::to_string).collect::<Vec<_>>();
+        assert_eq!(
+            expanded,
+            &[
+                "(fn safe-div (a b) (if (= b 0) nil (/ a b)))",
+                "(safe-div 10 2)"
+            ]
+        );
+        assert_eq!(eval(sample), Value::Signed32(5));
+    }
+
+    #[test]
+    fn macro_gensym_avoids_capture() {
+        let sample = "
+            (defmacro my-or (a b)
+              (let tmp (gensym 'tmp)
+                `(let ,tmp ,a (if ,tmp ,tmp ,b))))
+            (let tmp 5 (my-or false tmp))";
+        assert_eq!(eval(sample), Value::Signed32(5));
+
+        // Nothing the user writes can be a generated name
+        let sample = "
+            (defmacro swap-list (a b)
+              (let tmp (gensym 'tmp)
+                `(let ,tmp ,a (list ,b ,tmp))))
+            (let #:tmp1 \"user\" (swap-list 1 #:tmp1))";
+        assert_eq!(eval_display(sample), "(\"user\" 1)");
+        let name = Vm::new().unwrap().gensym("tmp");
+        assert!(lex(&name).is_err());
+        // Even when made by different VMs
+        assert_ne!(Vm::new().unwrap().gensym("tmp"), name);
+    }
+
+    #[test]
+    fn macros_call_functions_defined_before_them() {
+        let sample = "
+            (fn doubled (x) `(* 2 ,x))
+            (defmacro double (x) (doubled x))
+            (fn plus-one (x) `(+ ,x 1))
+            (defmacro inc (x) (plus-one x))
+            (list (double 21) (inc (double 1)))";
+        assert_eq!(eval_display(sample), "(42 3)");
+
+        // Only those defined before the macro is used
+        let sample = "(defmacro early (x) (later x)) (early 1) (fn later (x) x)";
+        let err = Vm::new().unwrap().eval(sample).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown function: later");
+    }
+
+    #[test]
+    fn macro_must_be_toplevel() {
+        let sample = "(fn f x (defmacro m (a) a))";
+        assert!(Vm::new().unwrap().eval(sample).is_err());
+    }
+
+    /// A directory under the system temp dir, removed when dropped, so it is
+    /// cleaned up however the test ends.
+    struct TempDir(PathBuf);
+
+    impl std::ops::Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Writes `files` into a fresh directory under the system temp dir.
+    fn write_files(test: &str, files: &[(&str, &str)]) -> TempDir {
+        let dir = std::env::temp_dir().join(format!("lithos-{test}-{}", std::process::id()));
+        let dir = TempDir(dir);
+        std::fs::create_dir_all(&*dir).unwrap();
+        for (name, content) in files {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    fn run_program(forms: &[Value]) -> Value {
+        let bytecode = generate(forms).unwrap();
+        Vm::new().unwrap().eval_bytecode(&bytecode).unwrap()
+    }
+
+    #[test]
+    fn modules_namespaces_and_cache() {
+        let dir = write_files(
+            "namespaces",
+            &[
+                ("a.li", "(fn helper n (+ n 1)) (fn from-a n (helper n))"),
+                ("b.li", "(import a :only (from-a)) (fn helper n (* n 10))"),
+                (
+                    "main.li",
+                    "(import a :only (from-a)) (import \"b.li\") (+ (from-a 1) (helper 2) (a/helper 3))",
+                ),
+            ],
+        );
+        let forms = Loader::new(Vec::new())
+            .load_program(&dir.join("main.li"))
+            .unwrap();
+        let definitions = forms
+            .iter()
+            .filter(|form| form.to_string().starts_with("(fn a/helper"))
+            .count();
+        assert_eq!(definitions, 1);
+        assert_eq!(run_program(&forms), Value::Signed32(2 + 20 + 4));
+    }
+
+    #[test]
+    fn modules_in_different_directories_have_their_own_namespaces() {
+        let dir = write_files(
+            "directories",
+            &[
+                ("a/util.li", "(fn helper n (+ n 1)) (fn inc n (helper n))"),
+                (
+                    "b/util.li",
+                    "(fn helper n (* n 10)) (fn scale n (helper n))",
+                ),
+                ("lib/c/util.li", "(fn helper n (- n))"),
+                (
+                    "main.li",
+                    "(import \"a/util.li\" :only (inc)) (import \"b/util.li\" :only (scale))
+                     (import \"c/util.li\" :only (helper))
+                     (list (inc 1) (scale 2) (a/util/helper 3) (b/util/helper 4) (helper 5))",
+                ),
+            ],
+        );
+        let forms = Loader::new(vec![dir.join("lib")])
+            .load_program(&dir.join("main.li"))
+            .unwrap();
+        assert_eq!(run_program(&forms).to_string(), "(2 20 4 40 -5)");
+        assert!(forms
+            .iter()
+            .any(|form| form.to_string().starts_with("(fn c/util/helper")));
+    }
+
+    #[test]
+    fn modules_report_clashes_and_cycles() {
+        let dir = write_files(
+            "clashes",
+            &[
+                ("a.li", "(fn helper n n)"),
+                ("b.li", "(fn helper n n)"),
+                ("clash.li", "(import a) (import b) (helper 1)"),
+                ("c.li", "(import d)"),
+                ("d.li", "(import c)"),
+                ("lib/e.li", "(fn e n n)"),
+                ("search.li", "(import e) (e 1)"),
+            ],
+        );
+        let clash = Loader::new(Vec::new()).load_program(&dir.join("clash.li"));
+        assert!(clash.unwrap_err().to_string().contains("Name clash"));
+        let cycle = Loader::new(Vec::new()).load_program(&dir.join("c.li"));
+        assert!(cycle.unwrap_err().to_string().contains("Import cycle"));
+
+        let missing = Loader::new(Vec::new()).load_program(&dir.join("search.li"));
+        assert!(missing.is_err());
+        let found = Loader::new(vec![dir.join("lib")]).load_program(&dir.join("search.li"));
+        assert_eq!(run_program(&found.unwrap()), Value::Signed32(1));
+    }
+
+    #[test]
+    fn lex_lossless_keeps_trivia() {
+        let sample = "(a ; hi\n 'b) ";
+        let toks = lex_lossless(sample).unwrap();
+        let toks = toks.into_iter().map(|(tok, _)| tok).collect::<Vec<_>>();
+        assert_eq!(
+            toks,
+            vec![
+                T::Open,
+                T::Symbol(S::Ident("a")),
+                T::Whitespace(" "),
+                T::Comment("; hi"),
+                T::Whitespace("\n "),
+                T::Quote,
+                T::Symbol(S::Ident("b")),
+                T::Close,
+                T::Whitespace(" "),
+            ]
+        );
+    }
+
+    #[test]
+    fn cst_round_trips() {
+        let samples = [
+            include_str!("../ops.li"),
+            "",
+            "  \n",
+            "; only a comment",
+            "(fn  f (a b)\n\t; body\n  (+ a  b) )  ; trailing\n\n",
+            "'  x `(a ,b ,@ (c)) ' ; note\n()",
+            "(echo \"a ; not a comment\")\r\n",
+            include_str!("../fixtures/fmt/comments.li"),
+            "('#; a #|x|# b #;#;c d e)",
+        ];
+        for sample in samples {
+            let cst = Cst::parse(sample).unwrap();
+            let printed = cst.iter().map(ToString::to_string).collect::<String>();
+            assert_eq!(printed, sample);
+        }
+    }
+
+    #[test]
+    fn cst_structure() {
+        let cst = Cst::parse("(a 'b) ; c").unwrap();
+        let expected = vec![
+            Cst::List(vec![
+                Cst::Atom("a"),
+                Cst::Whitespace(" "),
+                Cst::Quoted("'", vec![Cst::Atom("b")]),
+            ]),
+            Cst::Whitespace(" "),
+            Cst::Comment("; c"),
+        ];
+        assert_eq!(cst, expected);
+
+        let cst = Cst::parse("' ; why\n\"s\"").unwrap();
+        let expected = vec![Cst::Quoted(
+            "'",
+            vec![
+                Cst::Whitespace(" "),
+                Cst::Comment("; why"),
+                Cst::Whitespace("\n"),
+                Cst::Atom("\"s\""),
+            ],
+        )];
+        assert_eq!(cst, expected);
+
+        assert!(Cst::parse("(a").is_err());
+        assert!(Cst::parse("a)").is_err());
+        assert!(Cst::parse("( ')").is_err());
+        assert!(Cst::parse("' ").is_err());
+    }
+
+    #[test]
+    fn fmt_fixtures() {
+        let narrow = Config {
+            width: 40,
+            ..Config::default()
+        };
+        let fixtures = [
+            (
+                include_str!("../ops.li"),
+                include_str!("../ops.li"),
+                Config::default(),
+            ),
+            (
+                include_str!("../fixtures/fmt/comments.li"),
+                include_str!("../fixtures/fmt/comments.expected.li"),
+                narrow,
+            ),
+            (
+                include_str!("../fixtures/fmt/long.li"),
+                include_str!("../fixtures/fmt/long.expected.li"),
+                narrow,
+            ),
+        ];
+        for (src, expected, config) in fixtures {
+            let formatted = format(src, &config).unwrap();
+            assert_eq!(formatted, expected);
+            assert_eq!(format(&formatted, &config).unwrap(), formatted);
+        }
+    }
+
+    #[test]
+    fn fmt_indent_styles() {
+        let sample = "(fn f x (+ x 1))";
+        let tabs = Config {
+            indent: Indent::Tabs,
+            ..Config::default()
+        };
+        assert_eq!(format(sample, &tabs).unwrap(), "(fn f x\n\t(+ x 1))\n");
+        let four = Config {
+            indent: Indent::Spaces(4),
+            ..Config::default()
+        };
+        assert_eq!(format(sample, &four).unwrap(), "(fn f x\n    (+ x 1))\n");
+        assert_eq!(format("  \n", &four).unwrap(), "");
+        assert!(format("(a", &four).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn fmt_is_idempotent(width in 1..100usize, tabs: bool) {
+            let config = Config {
+                width,
+                indent: if tabs { Indent::Tabs } else { Indent::Spaces(2) },
+            };
+            let samples = [
+                include_str!("../ops.li"),
+                include_str!("../fixtures/fmt/comments.li"),
+                include_str!("../fixtures/fmt/long.li"),
+            ];
+            for sample in samples {
+                let formatted = format(sample, &config).unwrap();
+                prop_assert_eq!(format(&formatted, &config).unwrap(), formatted);
+            }
+        }
+    }
+
+    #[test]
+    fn block_and_datum_comments() {
+        let sample = "#| a #| nested |# b |# (x #;y) #|c|#";
+        let toks = lex(sample).unwrap();
+        let expected = vec![
+            T::Open,
+            T::Symbol(S::Ident("x")),
+            T::DatumComment,
+            T::Symbol(S::Ident("y")),
+            T::Close,
+        ];
+        assert_eq!(toks, expected);
+        let toks = lex_lossless(sample).unwrap();
+        assert_eq!(toks[0].0, T::Comment("#| a #| nested |# b |#"));
+
+        assert_eq!(eval_display("(list 1 #;2 3 #| 4 |# 5)"), "(1 3 5)");
+        assert_eq!(eval_display("'(a #; #; b c d)"), "(a d)");
+        assert_eq!(eval_display("'(a #;'b '#;c d)"), "(a (quote d))");
+        assert_eq!(eval_display("#;(echo 1) (+ 1 1)"), "2");
+
+        assert!(lex("#| a #| b |# (x)").is_err());
+        let diagnostics = check("(x #;)");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "1:4: Expected expression after #;"
+        );
+        assert_eq!(check("(x) #;").len(), 1);
+    }
+
+    #[test]
+    fn lexer_end_of_input() {
+        assert_eq!(lex("foo").unwrap(), vec![T::Symbol(S::Ident("foo"))]);
+        assert_eq!(lex("(a) 42").unwrap()[3], T::Symbol(S::Number(42)));
+        assert_eq!(lex("(a) ; no newline").unwrap().len(), 3);
+        assert_eq!(lex("(a)  \n\t\n").unwrap().len(), 3);
+        assert_eq!(lex("").unwrap(), vec![]);
+        assert_eq!(lex(" ; only trivia").unwrap(), vec![]);
+        assert!(lex("\"open").is_err());
+
+        let many_comments = "; c\n".repeat(100_000) + "x";
+        assert_eq!(lex(&many_comments).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn lexer_non_ascii() {
+        let toks = lex("(echo \"héllo\") λ-x ; ñ").unwrap();
+        assert_eq!(toks[2], T::Symbol(S::StringLiteral("héllo")));
+        assert_eq!(toks[4], T::Symbol(S::Ident("λ-x")));
+        assert_eq!(eval_display("\"héllo wörld\""), "héllo wörld");
+
+        let (toks, _) = lex_spanned("é (ü)");
+        assert_eq!(toks[1].1, Span { start: 3, end: 4 });
+    }
+
+    fn lexeme() -> impl Strategy<Value = String> {
+        prop_oneof![
+            Just("(".to_string()),
+            Just(")".to_string()),
+            Just("'".to_string()),
+            Just(",@".to_string()),
+            Just("#;".to_string()),
+            "[a-zA-Zéλß!?*<=+-]{1,6}",
+            "-?[0-9]{1,6}",
+            "\"[^\"]{0,8}\"",
+            "[ \t\r\n]{1,3}",
+            ";[^\n]{0,8}\n",
+            "#\\|[^|#]{0,8}\\|#",
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn lexer_never_panics(src in "\\PC*") {
+            let _ = lex(&src);
+            let _ = check(&src);
+            if let Ok(toks) = lex_lossless(&src) {
+                let text = toks.iter().map(|(_, span)| &src[span.start..span.end]);
+                prop_assert_eq!(text.collect::<String>(), src.clone());
+            }
+        }
+
+        #[test]
+        fn lexer_accepts_well_formed_source(lexemes in prop::collection::vec(lexeme(), 0..40)) {
+            let src = lexemes.join(" ");
+            let toks = lex_lossless(&src).unwrap();
+            let mut end = 0;
+            for (_, span) in &toks {
+                prop_assert_eq!(span.start, end);
+                end = span.end;
+            }
+            prop_assert_eq!(end, src.len());
+
+            let plain = lex(&src).unwrap();
+            let kept = toks
+                .into_iter()
+                .map(|(tok, _)| tok)
+                .filter(|tok| !matches!(tok, T::Whitespace(_) | T::Comment(_)));
+            prop_assert_eq!(plain, kept.collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn identifier_characters() {
+        let toks = lex("(-> empty? set! <= ns/name #:g1 :only 1+ x_2 ñandú)").unwrap();
+        let idents = toks[1..toks.len() - 1].iter().map(|tok| match tok {
+            T::Symbol(S::Ident(ident)) => *ident,
+            other => panic!("Expected an identifier, got {other:?}"),
+        });
+        let expected = [
+            "->", "empty?", "set!", "<=", "ns/name", "#:g1", ":only", "1+", "x_2", "ñandú",
+        ];
+        assert_eq!(idents.collect::<Vec<_>>(), expected);
+
+        assert_eq!(lex("a\"b\"").unwrap().len(), 2);
+        assert_eq!(
+            lex("(a [b])").unwrap_err().to_string(),
+            "Unexpected character '['"
+        );
+        assert!(lex("(echo 🙂)").is_err());
+        assert!(lex("\u{301}a").is_err());
+    }
+
+    #[test]
+    fn identifiers_are_nfc_normalized() {
+        let composed = "caf\u{e9}";
+        let decomposed = "cafe\u{301}";
+        let sample = format!("(fn {composed} x (+ x 1)) ({decomposed} 1)");
+        assert_eq!(run_program(&read(&sample).unwrap()), Value::Signed32(2));
+        let sample = format!("(let {decomposed} 5 {composed})");
+        assert_eq!(eval(&sample), Value::Signed32(5));
+    }
+
+    #[test]
+    fn mixed_script_identifiers_warn() {
+        // The 'а' is Cyrillic
+        let warnings = mixed_script_warnings("(fn p\u{430}ypal x x) (naïve λόγος)");
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(
+            warnings[0].to_string(),
+            "1:5: Mixed-script identifier: p\u{430}ypal"
+        );
+        // Names after a character the lexer rejects are still checked
+        let warnings = mixed_script_warnings("[ p\u{430}ypal");
+        assert_eq!(warnings.len(), 1);
+    }
+
+    proptest! {
+        #[test]
+        fn stream_lexer_matches_lex(
+            lexemes in prop::collection::vec(lexeme(), 0..40),
+            chunk in 1..16usize,
+        ) {
+            let src = lexemes.join(" ");
+            let (expected, _) = lex_spanned(&src);
+            let expected = expected.into_iter().map(|(tok, span)| (tok.into(), span));
+
+            let reader = BufReader::with_capacity(chunk, src.as_bytes());
+            let streamed = StreamLexer::new(reader).collect::<anyhow::Result<Vec<_>>>().unwrap();
+            prop_assert_eq!(streamed, expected.collect::<Vec<(OwnedToken, Span)>>());
+
+            let reader = BufReader::with_capacity(chunk, src.as_bytes());
+            let forms = FormReader::new(reader).collect::<anyhow::Result<Vec<_>>>();
+            match read(&src) {
+                Ok(expected) => prop_assert_eq!(forms.unwrap(), expected),
+                Err(_) => prop_assert!(forms.is_err()),
+            }
+        }
+    }
+
+    /// Hands out its chunks one read at a time, counting them.
+    struct Chunks(Vec<&'static str>, Rc<Cell<usize>>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let Some(chunk) = self.0.get(self.1.get()) else {
+                return Ok(0);
+            };
+            self.1.set(self.1.get() + 1);
+            buf[..chunk.len()].copy_from_slice(chunk.as_bytes());
+            Ok(chunk.len())
+        }
+    }
+
+    #[test]
+    fn form_reader_is_incremental() {
+        let reads = Rc::new(Cell::new(0));
+        let chunks = vec!["(a 1", "2) (b \"x", " y\") 'c", "d\n", "(e"];
+        let mut forms = FormReader::new(BufReader::new(Chunks(chunks, reads.clone())));
+
+        assert_eq!(forms.next().unwrap().unwrap().to_string(), "(a 12)");
+        assert_eq!(reads.get(), 2);
+        assert_eq!(forms.next().unwrap().unwrap().to_string(), "(b \"x y\")");
+        assert_eq!(reads.get(), 3);
+        assert_eq!(forms.next().unwrap().unwrap().to_string(), "(quote cd)");
+        assert_eq!(reads.get(), 4);
+        assert!(forms.next().unwrap().is_err());
+        assert!(forms.next().is_none());
+    }
+
+    #[test]
+    fn stream_lexer_utf8_across_chunks() {
+        let src = "(echo \"héllo\") ñandú";
+        let reader = BufReader::with_capacity(1, src.as_bytes());
+        let toks = StreamLexer::new(reader)
+            .map(|tok| tok.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            toks[2],
+            OwnedToken::Symbol(OwnedSymbol::StringLiteral("héllo".into()))
+        );
+        assert_eq!(
+            toks[4],
+            OwnedToken::Symbol(OwnedSymbol::Ident("ñandú".into()))
+        );
+
+        let invalid: &[u8] = b"(a \xff)";
+        assert!(StreamLexer::new(invalid).any(|tok| tok.is_err()));
+        let truncated: &[u8] = b"(a \xc3";
+        assert!(StreamLexer::new(truncated).any(|tok| tok.is_err()));
+    }
+
+    #[test]
+    fn symbols_are_interned() {
+        let a = SymbolId::intern("interned-symbol");
+        assert_eq!(SymbolId::intern("interned-symbol"), a);
+        assert_ne!(SymbolId::intern("interned-other"), a);
+        assert_eq!(&*a.name(), "interned-symbol");
+
+        let bytecode = compile("(interned-f interned-x)").unwrap();
+        let expected = SymbolId::intern("interned-f");
+        assert!(matches!(bytecode[..], [_, Instruction::Call(0, f, 1, 1)] if f == expected));
+        assert_eq!(
+            format!("{:?}", bytecode[1]),
+            "Call(0, \"interned-f\", 1, 1)"
+        );
+    }
+
+    #[test]
+    fn parameters_shadow_until_return() {
+        let sample = "
+            (fn f n (+ n 1))
+            (fn g n (f (* n 10)))
+            (let n 5 (list (f 1) (g 2) n))
+        ";
+        assert_eq!(eval_display(sample), "(2 21 5)");
+        assert!(Vm::new().unwrap().eval("(fn f n n) (f 1) n").is_err());
+    }
+
+    #[test]
+    fn locals_resolve_to_slots() {
+        let bytecode = compile("(fn f (a b) (let c (+ a b) (list a b c)))").unwrap();
+        let [Instruction::DefineFunction(function)] = &bytecode[..] else {
+            panic!("expected a single function definition, got {bytecode:?}");
+        };
+        let bytecode = function.bytecode();
+        // The result gets register 2, so `c` is in 3
+        assert!(matches!(
+            bytecode[0],
+            Instruction::Operation(Op::Add, 3, 0, 1)
+        ));
+        let moves = bytecode
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Move(_, src) => Some(*src),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(moves, [3, 1, 0]); // Arguments are evaluated last first
+        assert!(matches!(bytecode.last(), Some(Instruction::Return(2))));
+
+        assert_eq!(eval_display("(let x 1 (list (let x 2 x) x))"), "(2 1)");
+        assert_eq!(
+            eval_display("(let x 1 (let y 2 (let x 3 (list x y))))"),
+            "(3 2)"
+        );
+    }
+
+    #[test]
+    fn functions_capture_upvalues() {
+        let sample = "
+            (fn adder x (fn add y (+ x y)))
+            (adder 10)
+            (add 5)
+        ";
+        assert_eq!(eval_display(sample), "15");
+
+        // Captured through an intermediate function that never uses `x` itself
+        let sample = "
+            (fn outer x (fn middle y (fn inner z (list x y z))))
+            (outer 1)
+            (middle 2)
+            (inner 3)
+        ";
+        assert_eq!(eval_display(sample), "(1 2 3)");
+
+        assert_eq!(
+            eval_display("(fn twice (f x) (f (f x))) (fn inc n (+ n 1)) (twice inc 5)"),
+            "7"
+        );
+        assert_eq!(eval_display("(let f first (f '(1 2)))"), "1");
+    }
+
+    #[test]
+    fn registers_belong_to_their_frame() {
+        let sample = "
+            (fn count-down n (if (< n 1) '() (cons n (count-down (- n 1)))))
+            (let xs (count-down 3) (list xs (count-down 1) xs))
+        ";
+        assert_eq!(eval_display(sample), "((3 2 1) (1) (3 2 1))");
+        assert_eq!(
+            eval_display("(list (- 5) (* 2 3 4) (- 10 1 2))"),
+            "(-5 24 7)"
+        );
+
+        let mut vm = Vm::new().unwrap();
+        for src in ["(+ 1 \"a\")", "(- 'a)", "(* 1 2 nil)"] {
+            let err = vm.eval(src).unwrap_err();
+            assert_eq!(err.to_string(), "Expected number", "{src}");
+        }
+        // An error unwinds every frame, and the VM carries on
+        vm.eval("(fn f x (if (< x 1) (+ x nil) (list x (f (- x 1)))))")
+            .unwrap();
+        assert!(vm.eval("(list 1 (f 3))").is_err());
+        assert_eq!(
+            vm.eval("(let y 2 (list y (f 0 1)))")
+                .unwrap_err()
+                .to_string(),
+            "Expected 1 args, got 2"
+        );
+        assert_eq!(vm.eval("(let y 2 y)").unwrap(), Value::Signed32(2));
+
+        // Arguments past the end of the caller's frame
+        vm.eval("(fn g () 7) (fn h x (list x))").unwrap();
+        let code = assemble("Call r0 g r5 0  Load r7 1  Call r1 h r7 1  MakeList r0 r0 2").unwrap();
+        assert_eq!(vm.eval_bytecode(&code).unwrap().to_string(), "(7 (1))");
+    }
+
+    #[test]
+    fn bytecode_round_trips() {
+        let sample = "
+            (fn adder x (fn add y (+ x y)))
+            (adder 10)
+            (let xs `(1 ,@(list 2 3) \"s\" sym (nested ()) true)
+              (list (add 5) xs (if (< 1 2) 'yes 'no)))
+        ";
+        let bytes = encode(&compile(sample).unwrap()).unwrap();
+        assert!(bytes.starts_with(MAGIC));
+        let bytecode = decode(&bytes).unwrap();
+        assert_eq!(encode(&bytecode).unwrap(), bytes);
+
+        let value = Vm::new().unwrap().eval_bytecode(&bytecode).unwrap();
+        assert_eq!(value, eval(sample));
+    }
+
+    #[test]
+    fn bytecode_shares_functions() {
+        // Each function defines the one before it ten times over, so copying
+        // definitions out would take 10^20 instructions
+        let mut function = Rc::new(Function::User {
+            name: "f".to_string(),
+            args: Vec::new(),
+            captures: Vec::new(),
+            upvalues: Default::default(),
+            bytecode: vec![Instruction::Return(0)],
+        });
+        for _ in 0..20 {
+            let mut bytecode = vec![Instruction::DefineFunction(function); 10];
+            bytecode.push(Instruction::Return(0));
+            function = Rc::new(Function::User {
+                name: "f".to_string(),
+                args: Vec::new(),
+                captures: Vec::new(),
+                upvalues: Default::default(),
+                bytecode,
+            });
+        }
+        let code = vec![Instruction::DefineFunction(function)];
+        let bytes = encode(&code).unwrap();
+        assert!(bytes.len() < 2_000, "{} bytes", bytes.len());
+
+        let decoded = decode(&bytes).unwrap();
+        let [Instruction::DefineFunction(function)] = &decoded[..] else {
+            panic!("expected a single function definition");
+        };
+        let [Instruction::DefineFunction(a), Instruction::DefineFunction(b), ..] =
+            function.bytecode()
+        else {
+            panic!("expected function definitions");
+        };
+        assert!(Rc::ptr_eq(a, b));
+        assert_eq!(encode(&decoded).unwrap(), bytes);
+        assert!(Vm::new().unwrap().eval_bytecode(&decoded).is_ok());
+    }
+
+    #[test]
+    fn bytecode_rejects_bad_files() {
+        let bytes = encode(&compile("(fn f x (list x \"x\")) (f 1)").unwrap()).unwrap();
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[4] += 1;
+        let err = decode(&wrong_version).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::BytecodeVersion(version)) if *version == VERSION + 1
+        ));
+
+        assert!(decode(b"#!/usr/bin/env lithos").is_err());
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(decode(&trailing).is_err());
+        for len in 0..bytes.len() {
+            assert!(decode(&bytes[..len]).is_err(), "decoded {len} bytes");
+        }
+
+        // Upvalues the code can't have, which the VM doesn't check for itself
+        let capturing = |captures: Vec<Capture>, bytecode: Vec<Instruction>| {
+            Instruction::DefineFunction(Rc::new(Function::User {
+                name: "f".to_string(),
+                args: Vec::new(),
+                captures,
+                upvalues: Default::default(),
+                bytecode,
+            }))
+        };
+        let loads = |index| vec![Instruction::LoadUpvalue(0, index), Instruction::Return(0)];
+        let invalid = [
+            vec![Instruction::LoadUpvalue(0, 5)],
+            vec![capturing(vec![Capture::Upvalue(0)], Vec::new())],
+            vec![
+                capturing(vec![Capture::Local(0)], loads(1)),
+                Instruction::Call(0, SymbolId::intern("f"), 0, 0),
+            ],
+        ];
+        for code in invalid {
+            let err = decode(&encode(&code).unwrap()).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "Invalid bytecode: upvalue index out of range"
+            );
+            let err = Vm::new().unwrap().eval_bytecode(&code).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "Invalid bytecode: upvalue index out of range"
+            );
+        }
+        let valid = vec![capturing(vec![Capture::Local(0)], loads(0))];
+        assert!(decode(&encode(&valid).unwrap()).is_ok());
+
+        // Constants nested deeper than the reader's stack allows
+        let mut nested = MAGIC.to_vec();
+        nested.extend(VERSION.to_le_bytes());
+        nested.extend([0, 0, 0, 0, 1, 0, 0, 0]);
+        for _ in 0..100_000 {
+            nested.extend([5, 1, 0, 0, 0]);
+        }
+        nested.push(0);
+        nested.extend([0; 8]);
+        let err = decode(&nested).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid bytecode: constant nested too deeply"
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn bytecode_decode_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+            let mut file = MAGIC.to_vec();
+            file.extend(VERSION.to_le_bytes());
+            file.extend(bytes);
+            let _ = decode(&file);
+        }
+    }
+
+    #[test]
+    fn disassembly_assembles_back() {
+        let sample = "
+            (fn adder x (fn add y (+ x y)))
+            (adder 10)
+            (let xs `(1 ,@(list 2 3) \"s;\" sym (nested ()))
+              (list (add 5) xs (if (< 1 2) 'yes nil) (map add '(1 2))))
+        ";
+        let bytecode = compile(sample).unwrap();
+        let text = disassemble(&bytecode, &[(0, "adder".to_string())]);
+        assert!(text.starts_with("; adder\n0000  DefineFunction adder (x) ()\n"));
+        assert!(text.contains("          0000  DefineFunction add (y) ((local r0))\n"));
+
+        let assembled = assemble(&text).unwrap();
+        assert_eq!(encode(&assembled).unwrap(), encode(&bytecode).unwrap());
+        assert_eq!(disassemble(&assembled, &[]), disassemble(&bytecode, &[]));
+    }
+
+    #[test]
+    fn constants_survive_assembly() {
+        let list = |items: Vec<Value>| Value::List(items.into());
+        let symbol = |name: &str| Value::Symbol(name.into());
+        let constants = [
+            Value::Nil,
+            Value::Bool(false),
+            symbol("nil"),
+            list(vec![]),
+            list(vec![Value::Signed32(1), Value::Nil, Value::Bool(true)]),
+            list(vec![
+                symbol("false"),
+                list(vec![Value::Bool(false), Value::String("s".into())]),
+                list(vec![symbol("a"), list(vec![symbol("nil")])]),
+            ]),
+        ];
+        for constant in constants {
+            let text = disassemble(&[Instruction::Load(0, constant.clone())], &[]);
+            let [Instruction::Load(0, value)] = &assemble(&text).unwrap()[..] else {
+                panic!("expected a single load from {text}");
+            };
+            assert_eq!(*value, constant, "{text}");
+        }
+        assert_eq!(
+            disassemble(&[Instruction::Load(0, eval("(list 1 nil '(a))"))], &[]),
+            "0000  Load r0 (list 1 nil '(a))\n"
+        );
+    }
+
+    #[test]
+    fn assemble_by_hand() {
+        let src = "
+            DefineFunction twice (f x) ()
+                Move r3 r1 CallValue r2 r0 r3 1
+                CallValue r2 r0 r2 1
+                Return r2
+            end
+            Load r2 5 LoadGlobal r1 inc Call r0 twice r1 2
+            JumpUnless r0 2
+            Load r0 '(a \"b\") ; skipped
+            Jump 1
+            Load r0 nil
+        ";
+        let mut vm = Vm::new().unwrap();
+        vm.eval("(fn inc n (+ n 1))").unwrap();
+        assert_eq!(
+            vm.eval_bytecode(&assemble(src).unwrap()).unwrap(),
+            eval("'(a \"b\")")
+        );
+
+        let error = |src| assemble(src).unwrap_err().to_string();
+        assert_eq!(error("Push 1"), "Unknown instruction: Push");
+        assert_eq!(
+            error("0000 Load r0 1 0002 Load r0 2"),
+            "Expected instruction offsets in order"
+        );
+        assert_eq!(
+            error("DefineFunction f () () Load r0 1"),
+            "Expected end of function"
+        );
+        assert_eq!(error("Load r0 1 end"), "Unknown instruction: end");
+        assert_eq!(error("Load r0 x"), "Expected quoted symbol");
+        assert_eq!(error("Move r0 0"), "Expected register");
+        assert!(assemble("Move r0 r-1").is_err());
+        assert!(assemble("Return r65536").is_err());
+
+        // Upvalues must be among the captures of the function loading them
+        let upvalue = "Expected index of a captured upvalue";
+        assert_eq!(error("LoadUpvalue r0 0"), upvalue);
+        assert_eq!(
+            error("DefineFunction f () ((local r1)) LoadUpvalue r0 1 Return r0 end"),
+            upvalue
+        );
+        assert_eq!(error("DefineFunction f () ((upvalue 0)) end"), upvalue);
+        let nested = "
+            DefineFunction f () ((local r1))
+                DefineFunction g () ((upvalue 0))
+                    LoadUpvalue r0 0
+                    Return r0
+                end
+            end
+        ";
+        assert!(assemble(nested).is_ok());
+    }
+
+    #[test]
+    fn forms_know_their_lines() {
+        let src = "; header\n(a)\n\n#;(skipped\n) 'b \"c\nd\" #| x\n|# e\n";
+        let lines = read_with_lines(src)
+            .unwrap()
+            .into_iter()
+            .map(|(form, line)| (form.to_string(), line))
+            .collect::<Vec<_>>();
+        let expected = [("(a)", 2), ("(quote b)", 5), ("c\nd", 5), ("e", 7)];
+        let expected = expected.map(|(form, line)| (form.to_string(), line));
+        assert_eq!(lines, expected);
+
+        let dir = write_files(
+            "locations",
+            &[
+                ("main.li", "(import lib)\n\n(f 1)"),
+                ("lib.li", "\n(fn f x x)"),
+            ],
+        );
+        let locations = Loader::new(vec![])
+            .load_program_with_locations(&dir.join("main.li"))
+            .unwrap()
+            .into_iter()
+            .map(|(_, location)| (location.path.file_name().unwrap().to_owned(), location.line))
+            .collect::<Vec<_>>();
+        assert_eq!(locations, [("lib.li".into(), 2), ("main.li".into(), 3)]);
+    }
+
+    fn optimized(src: &str, level: u8) -> String {
+        disassemble(&optimize(compile(src).unwrap(), level), &[])
+    }
+
+    #[test]
+    fn optimizer_folds_constants() {
+        assert_eq!(optimized("(* 2 (+ 3 4))", 1), "0000  Load r0 14\n");
+        assert_eq!(optimized("(- 5)", 1), "0000  Load r0 -5\n");
+        assert_eq!(optimized("(if true 1 (echo 2))", 1), "0000  Load r0 1\n");
+        assert_eq!(optimized("(if nil (echo 1))", 1), "0000  Load r0 nil\n");
+        assert_eq!(
+            optimized("`(1 (2 ,(+ 1 2)))", 1),
+            "0000  Load r0 '(1 (2 3))\n"
+        );
+        assert_eq!(optimized("`(,false)", 1), "0000  Load r0 (list false)\n");
+        assert_eq!(optimized("`(1 ,nil)", 1), "0000  Load r0 (list 1 nil)\n");
+        assert_eq!(
+            optimized("(fn f x (+ x (* 2 3)))", 1),
+            "0000  DefineFunction f (x) ()\n          0000  Load r2 6\n          0001  Operation + r1 r0 r2\n          0002  Return r1\n      end\n"
+        );
+
+        // Left for the VM, which knows what to do about them
+        assert_eq!(optimized("(/ 1 0)", 2), optimized("(/ 1 0)", 0));
+        assert_eq!(optimized("(+ 1 \"a\")", 2), optimized("(+ 1 \"a\")", 0));
+        assert_eq!(
+            optimized("(+ 1 2)", 0),
+            "0000  Load r1 2\n0001  Load r2 1\n0002  Operation + r0 r2 r1\n"
+        );
+    }
+
+    #[test]
+    fn optimizer_keeps_jumps_right() {
+        let sample = "(fn f x (if x (if true (+ 1 2) 3) (list 4 x))) (list (f 1) (f nil))";
+        assert_eq!(
+            optimized(sample, 2),
+            assemble(
+                "
+                DefineFunction f (x) ()
+                    JumpUnless r0 2
+                    Load r1 3
+                    Jump 3
+                    Move r3 r0
+                    Load r2 4
+                    Call r1 list r2 2
+                    Return r1
+                end
+                Load r3 nil Call r2 f r3 1
+                Load r3 1 Call r1 f r3 1
+                Call r0 list r1 2
+                "
+            )
+            .map(|code| disassemble(&code, &[]))
+            .unwrap()
+        );
+        assert_eq!(eval_display(sample), "(3 (4 nil))");
+    }
+
+    #[test]
+    fn optimizer_propagates_constant_locals() {
+        assert_eq!(
+            optimized("(let x 5 (let y (+ x 1) (list y x)))", 2),
+            "0000  Load r4 5\n0001  Load r3 6\n0002  Call r0 list r3 2\n"
+        );
+        // Captured locals keep their values
+        let sample = "(fn f x (let y 1 (fn g z (+ y z))))";
+        assert!(optimized(sample, 2).contains("Load r2 1"));
+        assert_eq!(optimized("(let x 1 (let x 2 x))", 2), "0000  Load r0 2\n");
+    }
+
+    /// Arithmetic, lets, conditions and lists over the locals `a`, `b` and `c`.
+    fn arb_program() -> impl Strategy<Value = String> {
+        let leaf = prop_oneof![
+            (-5..5i32).prop_map(|n| n.to_string()),
+            prop::sample::select(vec!["a", "b", "c", "true", "false", "nil"])
+                .prop_map(String::from),
+        ];
+        let expr = leaf.prop_recursive(5, 48, 3, |inner| {
+            let var = prop::sample::select(vec!["a", "b", "c"]);
+            prop_oneof![
+                (
+                    prop::sample::select(vec!["+", "-", "*", "/"]),
+                    prop::collection::vec(inner.clone(), 0..4)
+                )
+                    .prop_map(|(op, args)| format!("({op} {})", args.join(" "))),
+                (var, inner.clone(), inner.clone())
+                    .prop_map(|(v, value, body)| format!("(let {v} {value} {body})")),
+                (inner.clone(), inner.clone(), inner.clone())
+                    .prop_map(|(c, t, e)| format!("(if {c} {t} {e})")),
+                (inner.clone(), inner.clone()).prop_map(|(a, b)| format!("(list {a} `({b} ,{a}))")),
+                (inner.clone(), inner).prop_map(|(a, b)| format!("(< {a} {b})")),
+            ]
+        });
+        expr.prop_map(|expr| format!("(let a 1 (let b 2 (let c 3 {expr})))"))
+    }
+
+    /// Programs like [`arb_program`], also calling small functions.
+    fn arb_program_with_calls() -> impl Strategy<Value = String> {
+        let helpers = "
+            (fn sq n (* n n))
+            (fn pick (c x y) (if c (list x y) (sq y)))
+            (fn twice (a b) (pick (< a b) (sq a) b))
+        ";
+        let call =
+            prop::sample::select(vec!["(sq {})", "(pick {} 1 {})", "(twice {} {})", "(+ {})"]);
+        (call, arb_program(), arb_program()).prop_map(move |(call, a, b)| {
+            let call = call.replacen("{}", &a, 1).replacen("{}", &b, 1);
+            format!("{helpers} (fn main x {call}) (main 0)")
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn optimizer_preserves_meaning(program in arb_program(), level in 1..=2u8) {
+            let run = |level| {
+                let bytecode = optimize(compile(&program).unwrap(), level);
+                Vm::new().unwrap().eval_bytecode(&bytecode).map_err(|err| err.to_string())
+            };
+            prop_assert_eq!(run(level), run(0));
+        }
+
+        #[test]
+        fn optimized_code_survives_assembly(program in arb_program(), level in 1..=2u8) {
+            let bytecode = optimize(compile(&program).unwrap(), level);
+            let reassembled = assemble(&disassemble(&bytecode, &[])).unwrap();
+            let run = |bytecode: &[Instruction]| {
+                Vm::new().unwrap().eval_bytecode(bytecode).map_err(|err| err.to_string())
+            };
+            prop_assert_eq!(run(&reassembled), run(&bytecode));
+        }
+
+        #[test]
+        fn inlining_preserves_meaning(program in arb_program_with_calls(), threshold in 0..20usize) {
+            let run = |level| {
+                let options = Options { level, inline_threshold: threshold };
+                let bytecode = compile_optimized(&program, &options).unwrap();
+                Vm::new().unwrap().eval_bytecode(&bytecode).map_err(|err| err.to_string())
+            };
+            prop_assert_eq!(run(2), run(0));
+        }
+    }
+
+    /// The code of each form of `src`, optimised together at level 2.
+    fn inlined(src: &str, threshold: usize) -> Vec<String> {
+        let forms = Expander::new().expand_all(read(src).unwrap()).unwrap();
+        let options = Options {
+            level: 2,
+            inline_threshold: threshold,
+        };
+        generate_optimized(&forms, &options)
+            .unwrap()
+            .iter()
+            .map(|code| disassemble(code, &[]))
+            .collect()
+    }
+
+    #[test]
+    fn small_functions_are_inlined() {
+        let square = "(fn square n (* n n))";
+        let forms = inlined(
+            &format!("{square} (square 16) (fn f x (square (+ x 1)))"),
+            3,
+        );
+        assert_eq!(forms[1], "0000  Load r0 256\n");
+        // The argument is in r2, and the body works after every register of `f`
+        assert!(forms[2].contains("          0002  Operation * r4 r2 r2\n"));
+        assert!(!forms[2].contains("Call"));
+
+        assert!(inlined(&format!("{square} (square 16)"), 1)[1].contains("Call r0 square r1 1"));
+        // Only calls that can't run before the definition
+        assert!(inlined(&format!("(square 2) {square}"), 3)[0].contains("Call r0 square r1 1"));
+        assert!(inlined(&format!("(fn f x (square x)) {square}"), 3)[0].contains("Call r1 square"));
+        // The arguments must match, or the call fails like it would have
+        assert!(inlined(&format!("{square} (square 1 2)"), 3)[1].contains("square r1 2"));
+        // Functions that may be redefined, or that call themselves
+        assert!(inlined(&format!("{square} {square} (square 2)"), 3)[2].contains("Call"));
+        assert!(inlined("(fn f n (f n)) (f 1)", 10)[1].contains("Call r0 f r1 1"));
+        assert!(inlined(&format!("(if true {square}) (square 2)"), 3)[1].contains("Call"));
+        assert!(inlined("(let k 1 (fn f n (+ n k))) (f 1)", 10)[1].contains("Call r0 f r1 1"));
+    }
+
+    #[test]
+    fn inlined_code_keeps_callers_locals() {
+        let sample = "
+            (fn pair (a b) (list a b))
+            (fn swap (a b) (pair b a))
+            (fn f (x y) (let z (+ x y) (list (swap x z) (if (< x y) (pair z y) z) y)))
+            (list (f 1 2) (f 3 1))
+        ";
+        let mut vm = Vm::new().unwrap();
+        let code = compile_optimized(sample, &Options::new(2)).unwrap();
+        assert!(!disassemble(&code, &[]).contains("Call pair"));
+        let value = vm.eval_bytecode(&code).unwrap();
+        assert_eq!(value.to_string(), "(((3 1) (3 2) 2) ((4 3) 4 1))");
+        assert_eq!(value, eval(sample));
+    }
+
+    #[test]
+    fn functions_are_shared_not_copied() {
+        // The same function every time it is read
+        assert_eq!(eval_display("(fn f x x) (= f f)"), "true");
+        assert_eq!(
+            eval_display("(fn f x x) (let g f (list (= g f) (g 1)))"),
+            "(true 1)"
+        );
+
+        // Redefining a function while it runs leaves the running code alone
+        let sample = "
+            (fn redefine z (fn f y (list 'new y)))
+            (fn f x (list (redefine 0) x))
+            (let first (f 1) (list first (f 2)))
+        ";
+        assert_eq!(eval_display(sample), "((nil 1) (new 2))");
+    }
+
+    fn global_function(vm: &mut Vm, name: &str) -> Rc<Function> {
+        match vm.eval(name).unwrap() {
+            Value::Function(func) => func,
+            other => panic!("{name} is {other}"),
+        }
+    }
+
+    #[test]
+    fn gc_frees_cycles() {
+        let mut vm = Vm::new().unwrap();
+        vm.eval("(fn make x (fn keep y x))").unwrap();
+
+        // A closure that captures a list holding the closure
+        vm.eval("(make 0)").unwrap();
+        let keep = global_function(&mut vm, "keep");
+        let env = vm
+            .alloc_list(vec![Value::Function(keep.clone()), Value::Signed32(1)])
+            .unwrap();
+        assert_eq!(keep.set_upvalue(0, env), Some(Value::Signed32(0)));
+        assert_eq!(keep.set_upvalue(1, Value::Nil), None);
+        let weak = Rc::downgrade(&keep);
+        drop(keep);
+
+        // Reachable through its global
+        assert_eq!(vm.gc(), 0);
+        assert_eq!(vm.eval("(first (rest (keep 0)))").unwrap().to_string(), "1");
+        assert_eq!(
+            vm.eval("(= (first (keep 0)) keep)").unwrap().to_string(),
+            "true"
+        );
+
+        // Once redefined, only the cycle keeps it alive
+        vm.eval("(make 1)").unwrap();
+        assert!(weak.upgrade().is_some());
+        let before = vm.heap_stats();
+        assert_eq!(vm.gc(), 2);
+        assert!(weak.upgrade().is_none());
+        let after = vm.heap_stats();
+        assert_eq!(after.collections, before.collections + 1);
+        assert_eq!(after.collected, before.collected + 2);
+        assert!(after.live_objects <= before.live_objects - 2);
+        assert!(after.live_bytes < before.live_bytes);
+
+        // A closure that captures itself, held from outside the VM for a while
+        let keep = global_function(&mut vm, "keep");
+        keep.set_upvalue(0, Value::Function(keep.clone()));
+        vm.eval("(make 2)").unwrap();
+        assert_eq!(vm.gc(), 0);
+        let result = vm.call(&Value::Function(keep.clone()), vec![Value::Nil]);
+        assert_eq!(result.unwrap(), Value::Function(keep.clone()));
+        let weak = Rc::downgrade(&keep);
+        drop(keep);
+        assert_eq!(vm.gc(), 1);
+        assert!(weak.upgrade().is_none());
+
+        // Cycles left when the VM goes away are freed with it
+        let keep = global_function(&mut vm, "keep");
+        keep.set_upvalue(0, Value::Function(keep.clone()));
+        let weak = Rc::downgrade(&keep);
+        drop(keep);
+        drop(vm);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn gc_runs_as_the_heap_grows() {
+        let mut vm = Vm::new().unwrap();
+        let before = vm.heap_stats();
+        vm.eval("(list 1 (list 2 3))").unwrap();
+        let after = vm.heap_stats();
+        assert_eq!(after.allocations, before.allocations + 2);
+        assert!(after.allocated_bytes > before.allocated_bytes);
+
+        let code = compile("(fn f x (list x x)) (f (list 1 2 3))").unwrap();
+        for _ in 0..10_000 {
+            vm.run(&code).unwrap();
+        }
+        let stats = vm.heap_stats();
+        assert!(stats.collections > 0);
+        assert!(stats.live_objects < 10_000, "{stats:?}");
+        assert_eq!(stats.collected, 0);
+    }
+
+    #[test]
+    fn limits_stop_runaway_code() {
+        let mut vm = Vm::new().unwrap();
+        vm.eval(
+            "
+            (fn count n (if (< n 1) 0 (+ 1 (count (- n 1)))))
+            (fn forever n (+ 1 (forever n)))
+            (fn hoard n (if (< n 1) '() (cons n (hoard (- n 1)))))
+            ",
+        )
+        .unwrap();
+        let limited = |vm: &mut Vm, limits: Limits, src: &str| {
+            vm.set_limits(limits);
+            let result = vm.eval(src).map_err(|err| err.to_string());
+            vm.set_limits(Limits::default());
+            result.map(|value| value.to_string())
+        };
+
+        let fuel = Limits {
+            instructions: Some(500),
+            ..Limits::default()
+        };
+        assert_eq!(limited(&mut vm, fuel, "(count 20)"), Ok("20".to_string()));
+        assert_eq!(
+            limited(&mut vm, fuel, "(count 100)"),
+            Err("Instruction limit of 500 reached".to_string())
+        );
+        // Calls made by builtins use up the same fuel
+        assert_eq!(
+            limited(&mut vm, fuel, "(map count (list 20 20 20 20 20 20))"),
+            Err("Instruction limit of 500 reached".to_string())
+        );
+
+        let depth = Limits {
+            call_depth: Some(100),
+            ..Limits::default()
+        };
+        assert_eq!(limited(&mut vm, depth, "(count 99)"), Ok("99".to_string()));
+        assert_eq!(
+            limited(&mut vm, depth, "(forever 1)"),
+            Err("Call depth limit of 100 reached".to_string())
+        );
+
+        // Calls don't nest on the Rust stack, so deep recursion is fine
+        assert_eq!(vm.eval("(count 50000)").unwrap(), Value::Signed32(50000));
+        let deep = Limits {
+            call_depth: Some(20_000),
+            ..Limits::default()
+        };
+        assert_eq!(
+            limited(&mut vm, deep, "(forever 1)"),
+            Err("Call depth limit of 20000 reached".to_string())
+        );
+        assert_eq!(
+            vm.eval("(forever 1)").unwrap_err().to_string(),
+            format!("Call depth limit of {DEFAULT_CALL_DEPTH} reached")
+        );
+        // Unlike calls through natives, which do
+        vm.eval("(fn through-map n (first (map through-map (list n))))")
+            .unwrap();
+        assert_eq!(
+            vm.eval("(through-map 1)").unwrap_err().to_string(),
+            format!("Natives calling back into the VM nested over {MAX_NESTED_RUNS} deep")
+        );
+
+        let registers = Limits {
+            registers: Some(50),
+            ..Limits::default()
+        };
+        assert_eq!(
+            limited(&mut vm, registers, "(count 100)"),
+            Err("Register limit of 50 reached".to_string())
+        );
+        let many = (0..60).map(|n| n.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            limited(&mut vm, registers, &format!("(list {})", many.join(" "))),
+            Err("Register limit of 50 reached".to_string())
+        );
+
+        let heap = Limits {
+            heap_bytes: Some(vm.heap_stats().live_bytes + 2_000),
+            ..Limits::default()
+        };
+        assert_eq!(
+            limited(&mut vm, heap, "(length (hoard 10))"),
+            Ok("10".to_string())
+        );
+        assert!(limited(&mut vm, heap, "(hoard 200)")
+            .unwrap_err()
+            .starts_with("Heap limit of"));
+
+        // Each error leaves the VM as it was
+        assert_eq!(vm.eval("(count 200)").unwrap(), Value::Signed32(200));
+        assert!(matches!(vm.eval("(hoard 200)").unwrap(), Value::List(_)));
+    }
+
+    #[test]
+    fn evaluation_can_be_cancelled() {
+        let mut vm = Vm::new().unwrap();
+        // Over 2^40 calls, but never more than 40 deep
+        vm.eval("(fn spin n (if (< n 1) 0 (+ (spin (- n 1)) (spin (- n 1)))))")
+            .unwrap();
+
+        // Cancel until it stops, in case the first one comes before the start
+        let token = vm.cancel_token();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let canceller = std::thread::spawn({
+            let stopped = stopped.clone();
+            move || {
+                while !stopped.load(atomic::Ordering::Relaxed) {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                    token.cancel();
+                }
+            }
+        });
+        let err = vm.eval("(let x 1 (list x (spin 40)))").unwrap_err();
+        stopped.store(true, atomic::Ordering::Relaxed);
+        canceller.join().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Interrupted)
+        ));
+
+        // The VM carries on, and cancelling while it is idle does nothing
+        vm.cancel_token().cancel();
+        assert_eq!(
+            vm.eval("(let x 1 (list x (spin 10)))").unwrap().to_string(),
+            "(1 0)"
+        );
+    }
 }
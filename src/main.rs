@@ -1,49 +1,336 @@
+use std::collections::HashMap;
 use std::env;
 use std::fs::read_to_string;
+use std::io::stdin;
+use std::io::stdout;
+use std::io::IsTerminal;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
 use std::process::ExitCode;
 
-use rust_lisp_parser::ast::Tree;
-use rust_lisp_parser::lexer::lex;
-use rust_lisp_parser::lexer::Symbol;
+use rust_lisp_parser::asm::disassemble;
+use rust_lisp_parser::ast::check;
+use rust_lisp_parser::bytecode::decode;
+use rust_lisp_parser::bytecode::encode;
+use rust_lisp_parser::expander::Expander;
+use rust_lisp_parser::format::format;
+use rust_lisp_parser::format::Config;
+use rust_lisp_parser::format::Indent;
+use rust_lisp_parser::lexer::mixed_script_warnings;
+use rust_lisp_parser::modules::Loader;
+use rust_lisp_parser::optimize::generate_optimized;
+use rust_lisp_parser::optimize::Options;
 use rust_lisp_parser::simulator::run;
-use rust_lisp_parser::simulator::Ast;
+use rust_lisp_parser::simulator::FormReader;
+use rust_lisp_parser::simulator::Value;
+use rust_lisp_parser::simulator::Vm;
 
 use anyhow::Result;
 use rust_lisp_parser::Error;
 
 fn main() -> Result<ExitCode> {
-    let mut args = env::args();
-    let path = args.nth(1).ok_or(Error::UnexpectedArgN(2, 1))?;
-    let content = read_to_string(path)?;
-
-    let tokens = lex(&content)?;
-    let tree = Tree::try_construct(&tokens)?;
-
-    match tree {
-        Tree::Branch(children) => {
-            // Probably too much collecting rite?
-            let bytecode = children
-                .iter()
-                .map(Ast::from_tree)
-                .collect::<Result<Vec<_>>>()?
-                .iter()
-                .map(Ast::generate)
-                .collect::<Result<Vec<_>>>()?
-                .into_iter()
-                .flatten()
-                .collect::<Vec<_>>();
-
-            for instruction in &bytecode {
-                println!("{instruction:?}");
+    match env::args().nth(1).as_deref() {
+        Some("fmt") => return fmt(env::args().skip(2)),
+        Some("compile") => return compile(env::args().skip(2)),
+        Some("run-bc") => return run_bc(env::args().skip(2)),
+        Some("disasm") => return disasm(env::args().skip(2)),
+        _ => {}
+    }
+
+    let mut expand_only = false;
+    let mut warn_confusables = false;
+    let mut path = None;
+
+    let (options, search_path, rest) = parse_options(
+        env::args().skip(1),
+        &["--expand", "--warn-confusables"],
+        &[],
+    )?;
+    for arg in rest {
+        match arg.as_str() {
+            "--expand" => expand_only = true,
+            "--warn-confusables" => warn_confusables = true,
+            _ => path = Some(arg),
+        }
+    }
+    let Some(path) = path else {
+        return repl(options);
+    };
+
+    let src = read_to_string(&path)?;
+    if warn_confusables {
+        for warning in mixed_script_warnings(&src) {
+            let (line, column) = (warning.line, warning.column);
+            eprintln!("{path}:{line}:{column}: warning: {}", warning.error);
+        }
+    }
+    if !report_diagnostics(&path, &src) {
+        return Ok(ExitCode::from(1));
+    }
+
+    let forms = Loader::new(search_path).load_program(Path::new(&path))?;
+
+    if expand_only {
+        for form in &forms {
+            match form {
+                Value::String(s) => println!("{s:?}"),
+                _ => println!("{form}"),
+            }
+        }
+        return Ok(ExitCode::from(0));
+    }
+
+    if let [Value::Signed32(n)] = forms.as_slice() {
+        return Ok(ExitCode::from(*n as u8));
+    }
+
+    run(generate_optimized(&forms, &options)?.concat())?;
+
+    Ok(ExitCode::from(0)) // TODO
+}
+
+/// Directories listed in `LITHOS_PATH`, searched for imports after those
+/// given with `-I`.
+fn default_search_path() -> Vec<PathBuf> {
+    match env::var_os("LITHOS_PATH") {
+        Some(paths) => env::split_paths(&paths).collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Reads the options shared by the commands that load programs: `-I dir`,
+/// `--inline-threshold N` and `-O` levels. The other arguments are returned
+/// in order for the command to handle, including its own `flags`, and its
+/// `valued` flags with the argument after each, whatever that looks like;
+/// anything else starting with `-` is an error.
+fn parse_options(
+    mut args: impl Iterator<Item = String>,
+    flags: &[&str],
+    valued: &[&str],
+) -> Result<(Options, Vec<PathBuf>, Vec<String>)> {
+    let mut options = Options::new(0);
+    let mut search_path = default_search_path();
+    let mut rest = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-I" => search_path.push(
+                args.next()
+                    .ok_or(Error::Expected("directory after -I"))?
+                    .into(),
+            ),
+            "--inline-threshold" => {
+                options.inline_threshold = args
+                    .next()
+                    .ok_or(Error::Expected(
+                        "instruction count after --inline-threshold",
+                    ))?
+                    .parse()?
+            }
+            flag if flag.starts_with("-O") => options.level = opt_level(flag)?,
+            flag if valued.contains(&flag) => {
+                rest.push(arg);
+                rest.extend(args.next());
             }
+            flag if flag.starts_with('-') && !flags.contains(&flag) => {
+                return Err(Error::UnknownOption(arg).into())
+            }
+            _ => rest.push(arg),
+        }
+    }
+    Ok((options, search_path, rest))
+}
 
-            run(bytecode)?;
+/// `-O` is `-O2`; otherwise the level follows the flag, as in `-O0`.
+fn opt_level(flag: &str) -> Result<u8> {
+    match &flag[2..] {
+        "" => Ok(2),
+        level => level
+            .parse()
+            .map_err(|_| Error::Expected("optimisation level after -O").into()),
+    }
+}
+
+/// Prints syntax errors in `src`, returning whether there were none.
+fn report_diagnostics(path: &str, src: &str) -> bool {
+    let diagnostics = check(src);
+    for diagnostic in &diagnostics {
+        eprintln!("{path}:{diagnostic}");
+    }
+    diagnostics.is_empty()
+}
+
+/// `compile [-I dir] [-o out.lbc] file` compiles a program and the modules it
+/// imports into one bytecode file, by default next to the source.
+fn compile(args: impl Iterator<Item = String>) -> Result<ExitCode> {
+    let (options, search_path, rest) = parse_options(args, &[], &["-o"])?;
+    let mut output = None;
+    let mut path = None;
+    let mut args = rest.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(args.next().ok_or(Error::Expected("file after -o"))?),
+            _ => path = Some(arg),
+        }
+    }
+    let path = path.ok_or(Error::Expected("file to compile"))?;
+
+    let src = read_to_string(&path)?;
+    if !report_diagnostics(&path, &src) {
+        return Ok(ExitCode::from(1));
+    }
+    let forms = Loader::new(search_path).load_program(Path::new(&path))?;
+    let bytes = encode(&generate_optimized(&forms, &options)?.concat())?;
+
+    let output = output.map_or_else(|| Path::new(&path).with_extension("lbc"), PathBuf::from);
+    std::fs::write(output, bytes)?;
+    Ok(ExitCode::from(0))
+}
+
+/// `run-bc file.lbc` runs a file written by `compile`.
+fn run_bc(args: impl Iterator<Item = String>) -> Result<ExitCode> {
+    let (_, _, rest) = parse_options(args, &[], &[])?;
+    let [path] = &rest[..] else {
+        return Err(Error::Expected("one bytecode file").into());
+    };
+    let bytecode = decode(&std::fs::read(path)?)?;
+    run(bytecode)?;
+    Ok(ExitCode::from(0))
+}
+
+/// `disasm [-I dir] file` prints the bytecode of a program as text. Source
+/// files are compiled first, and each top-level form is labelled with where
+/// it was written; `.lbc` files are shown as they are.
+fn disasm(args: impl Iterator<Item = String>) -> Result<ExitCode> {
+    let (options, search_path, rest) = parse_options(args, &[], &[])?;
+    let path = PathBuf::from(rest.last().ok_or(Error::Expected("file to disassemble"))?);
+
+    if path.extension().is_some_and(|ext| ext == "lbc") {
+        let bytecode = decode(&std::fs::read(&path)?)?;
+        print!("{}", disassemble(&bytecode, &[]));
+        return Ok(ExitCode::from(0));
+    }
+
+    let (forms, locations): (Vec<_>, Vec<_>) = Loader::new(search_path)
+        .load_program_with_locations(&path)?
+        .into_iter()
+        .unzip();
+    let mut bytecode = Vec::new();
+    let mut annotations = Vec::new();
+    let mut sources = HashMap::new();
+    for (code, location) in generate_optimized(&forms, &options)?
+        .into_iter()
+        .zip(locations)
+    {
+        if !sources.contains_key(&location.path) {
+            let src = read_to_string(&location.path)?;
+            sources.insert(location.path.clone(), src);
+        }
+        let line = sources[&location.path]
+            .lines()
+            .nth(location.line - 1)
+            .unwrap_or_default();
+        let file = location
+            .path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        annotations.push((
+            bytecode.len(),
+            format!("{file}:{}: {}", location.line, line.trim()),
+        ));
+        bytecode.extend(code);
+    }
+    print!("{}", disassemble(&bytecode, &annotations));
+    Ok(ExitCode::from(0))
+}
+
+/// `fmt [--check] [--width N] [--indent N|tab] files...` rewrites each file
+/// in the canonical style. With `--check`, files are only reported, and the
+/// exit code is 1 if any of them is not formatted.
+fn fmt(mut args: impl Iterator<Item = String>) -> Result<ExitCode> {
+    let mut config = Config::default();
+    let mut check_only = false;
+    let mut paths = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--check" => check_only = true,
+            "--width" => {
+                config.width = args
+                    .next()
+                    .ok_or(Error::Expected("line width after --width"))?
+                    .parse()?
+            }
+            "--indent" => {
+                config.indent = match args.next().as_deref() {
+                    Some("tab") => Indent::Tabs,
+                    Some(n) => Indent::Spaces(n.parse()?),
+                    None => {
+                        return Err(
+                            Error::Expected("number of spaces or 'tab' after --indent").into()
+                        )
+                    }
+                }
+            }
+            flag if flag.starts_with('-') => return Err(Error::UnknownOption(arg).into()),
+            _ => paths.push(arg),
+        }
+    }
+
+    let mut unformatted = false;
+    for path in &paths {
+        let src = read_to_string(path)?;
+        let formatted = format(&src, &config)?;
+        if formatted == src {
+            continue;
+        }
+        match check_only {
+            true => {
+                eprintln!("{path}: not formatted");
+                unformatted = true;
+            }
+            false => std::fs::write(path, formatted)?,
+        }
+    }
+    Ok(ExitCode::from(u8::from(unformatted)))
+}
+
+/// Without a file, forms are read from stdin and evaluated as soon as each
+/// is complete. Interactively, their values are printed and errors don't
+/// end the session; a program piped in stops at its first error.
+fn repl(options: Options) -> Result<ExitCode> {
+    let interactive = stdin().is_terminal();
+    let mut vm = Vm::new()?;
+    if interactive {
+        // Ctrl-C stops the form being evaluated rather than the REPL
+        let flag = vm.cancel_token().flag().clone();
+        signal_hook::flag::register(signal_hook::consts::SIGINT, flag)?;
+    }
+    let mut expander = Expander::new();
+    let prompt = || {
+        if interactive {
+            print!("> ");
+            stdout().flush()
+        } else {
+            Ok(())
+        }
+    };
 
-            Ok(ExitCode::from(0)) // TODO
+    prompt()?;
+    for form in FormReader::new(stdin().lock()) {
+        let value = form
+            .and_then(|form| expander.expand_all(vec![form]))
+            .and_then(|forms| generate_optimized(&forms, &options))
+            .map(|forms| forms.concat())
+            .and_then(|bytecode| vm.eval_bytecode(&bytecode));
+        match value {
+            Ok(Value::Nil) => {}
+            Ok(value) if interactive => println!("{value}"),
+            Ok(_) => {}
+            Err(err) if interactive => eprintln!("{err}"),
+            Err(err) => return Err(err),
         }
-        Tree::Leaf(leaf) => match leaf {
-            Symbol::Number(n) => Ok(ExitCode::from(*n as u8)),
-            _ => unimplemented!(),
-        },
+        prompt()?;
     }
+    Ok(ExitCode::from(0))
 }
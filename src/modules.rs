@@ -0,0 +1,285 @@
+use crate::expander::Expander;
+use crate::simulator::head_symbol;
+use crate::simulator::read_with_lines;
+use crate::simulator::Value;
+use crate::Error;
+
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+/// What a loaded module makes available to the files importing it.
+#[derive(Debug, Clone)]
+struct Module {
+    namespace: String,
+    exports: Vec<String>,
+}
+
+/// Where a top-level form was written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub path: PathBuf,
+    /// 1-based.
+    pub line: usize,
+}
+
+type LocatedForms = Vec<(Value, Location)>;
+
+/// Resolves `(import ...)` forms, loading every module at most once.
+///
+/// Each module gets a namespace named after its path without the `.li`,
+/// relative to the directory of the program or the search directory it was
+/// found in, whichever is closer. Its top-level functions are renamed into
+/// it, so `(fn square ...)` in `math.li` defines `math/square`, and in
+/// `geometry/math.li` defines `geometry/math/square`. Importers can always
+/// use the qualified name, and also get unqualified aliases for everything
+/// imported, or just the `:only` list.
+pub struct Loader {
+    search_path: Vec<PathBuf>,
+    /// The directory of the program being loaded.
+    root: Option<PathBuf>,
+    modules: HashMap<PathBuf, Module>,
+    /// Modules currently being loaded, innermost last, for cycle detection.
+    loading: Vec<PathBuf>,
+    /// Forms of every loaded module, dependencies before their importers.
+    forms: LocatedForms,
+}
+
+impl Loader {
+    /// Imports are looked up next to the importing file first, then in each
+    /// directory of `search_path` in order.
+    pub fn new(search_path: Vec<PathBuf>) -> Self {
+        Self {
+            search_path,
+            root: None,
+            modules: HashMap::new(),
+            loading: Vec::new(),
+            forms: Vec::new(),
+        }
+    }
+
+    /// Loads the program at `path`, returning its expanded forms preceded by
+    /// those of all the modules it depends on.
+    pub fn load_program(&mut self, path: &Path) -> Result<Vec<Value>> {
+        let forms = self.load_program_with_locations(path)?;
+        Ok(forms.into_iter().map(|(form, _)| form).collect())
+    }
+
+    /// Like [`Loader::load_program`], with where each form came from.
+    pub fn load_program_with_locations(&mut self, path: &Path) -> Result<LocatedForms> {
+        let path = path.canonicalize()?;
+        self.root = path.parent().map(Path::to_path_buf);
+        self.loading.push(path.clone());
+        let (forms, _) = self.load_file(&path, None)?;
+        self.loading.pop();
+
+        let mut program = std::mem::take(&mut self.forms);
+        program.extend(forms);
+        Ok(program)
+    }
+
+    fn load_module(&mut self, path: PathBuf) -> Result<Module> {
+        if let Some(module) = self.modules.get(&path) {
+            return Ok(module.clone());
+        }
+        if let Some(start) = self.loading.iter().position(|p| *p == path) {
+            let cycle = self.loading[start..]
+                .iter()
+                .chain([&path])
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>();
+            return Err(Error::ImportCycle(cycle.join(" -> ")).into());
+        }
+
+        let namespace = self.namespace(&path)?;
+        if self.modules.values().any(|m| m.namespace == namespace) {
+            return Err(Error::NameClash(format!("module {namespace}")).into());
+        }
+
+        self.loading.push(path.clone());
+        let (forms, exports) = self.load_file(&path, Some(&namespace))?;
+        self.loading.pop();
+
+        self.forms.extend(forms);
+        let module = Module { namespace, exports };
+        self.modules.insert(path, module.clone());
+        Ok(module)
+    }
+
+    /// Returns the module's expanded and renamed forms, and the names it defines.
+    fn load_file(
+        &mut self,
+        path: &Path,
+        namespace: Option<&str>,
+    ) -> Result<(LocatedForms, Vec<String>)> {
+        let src = read_to_string(path)?;
+
+        let mut renames = HashMap::new();
+        let mut body = Vec::new();
+        for (form, line) in read_with_lines(&src)? {
+            match &form {
+                Value::List(items) if matches!(head_symbol(items), Some("import" | "require")) => {
+                    self.import(path, items, &mut renames)?
+                }
+                _ => body.push((form, line)),
+            }
+        }
+
+        // One form at a time, to keep track of their lines
+        let mut expander = Expander::new();
+        let mut expanded = Vec::new();
+        for (form, line) in body {
+            for form in expander.expand_all(vec![form])? {
+                let path = path.to_path_buf();
+                expanded.push((form, Location { path, line }));
+            }
+        }
+
+        let definitions = expanded
+            .iter()
+            .filter_map(|(form, _)| match form {
+                Value::List(items) if head_symbol(items) == Some("fn") => match items.get(1) {
+                    Some(Value::Symbol(name)) => Some(name.to_string()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        for name in &definitions {
+            if renames.contains_key(name) {
+                return Err(Error::NameClash(name.clone()).into());
+            }
+        }
+        if let Some(namespace) = namespace {
+            for name in &definitions {
+                renames.insert(name.clone(), format!("{namespace}/{name}"));
+            }
+        }
+
+        let body = expanded
+            .into_iter()
+            .map(|(form, location)| (rename(&form, &renames, 0), location))
+            .collect();
+        Ok((body, definitions))
+    }
+
+    /// `(import module)` or `(import module :only (names...))`, where the
+    /// module is either a symbol or a path string.
+    fn import(
+        &mut self,
+        importer: &Path,
+        items: &[Value],
+        renames: &mut HashMap<String, String>,
+    ) -> Result<()> {
+        let (target, only) = match items {
+            [_, target] => (target, None),
+            [_, target, Value::Symbol(keyword), names] if &**keyword == ":only" => {
+                (target, Some(names))
+            }
+            _ => return Err(Error::Expected("(import module :only (names...))").into()),
+        };
+
+        let path = self.resolve(importer, target)?;
+        let module = self.load_module(path)?;
+
+        let names = match only {
+            None => module.exports.clone(),
+            Some(Value::Symbol(name)) => vec![name.to_string()],
+            Some(Value::List(names)) => names
+                .iter()
+                .map(|name| match name {
+                    Value::Symbol(name) => Ok(name.to_string()),
+                    _ => Err(Error::Expected("identifier").into()),
+                })
+                .collect::<Result<_>>()?,
+            Some(_) => return Err(Error::Expected("list of names to import").into()),
+        };
+
+        for name in names {
+            let qualified = format!("{}/{name}", module.namespace);
+            if !module.exports.contains(&name) {
+                return Err(Error::UnknownFunction(qualified).into());
+            }
+            match renames.insert(name.clone(), qualified.clone()) {
+                Some(previous) if previous != qualified => {
+                    return Err(Error::NameClash(name).into())
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// The namespace of the module at `path`: its path from the closest of
+    /// the program's directory and the search directories holding it, or
+    /// else just its file name, without the extension.
+    fn namespace(&self, path: &Path) -> Result<String> {
+        let relative = self
+            .root
+            .iter()
+            .cloned()
+            .chain(
+                self.search_path
+                    .iter()
+                    .filter_map(|dir| dir.canonicalize().ok()),
+            )
+            .filter_map(|dir| path.strip_prefix(dir).ok().map(Path::to_path_buf))
+            .min_by_key(|relative| relative.components().count())
+            .or_else(|| path.file_name().map(PathBuf::from))
+            .ok_or(Error::ModuleNotFound(path.display().to_string()))?;
+        let components = relative
+            .with_extension("")
+            .iter()
+            .map(|component| component.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        Ok(components.join("/"))
+    }
+
+    fn resolve(&self, importer: &Path, target: &Value) -> Result<PathBuf> {
+        let relative = match target {
+            Value::String(path) => PathBuf::from(&**path),
+            Value::Symbol(name) => PathBuf::from(format!("{name}.li")),
+            _ => return Err(Error::Expected("module name or path").into()),
+        };
+
+        let importer_dir = importer.parent().map(Path::to_path_buf);
+        importer_dir
+            .iter()
+            .chain(&self.search_path)
+            .map(|dir| dir.join(&relative))
+            .find(|candidate| candidate.is_file())
+            .ok_or(Error::ModuleNotFound(relative.display().to_string()))?
+            .canonicalize()
+            .map_err(Into::into)
+    }
+}
+
+/// Renames symbols throughout `form`, except where they are quoted data.
+/// `depth` counts the enclosing quasiquotes, like when generating templates.
+fn rename(form: &Value, renames: &HashMap<String, String>, depth: usize) -> Value {
+    let items = match form {
+        Value::Symbol(name) if depth == 0 => match renames.get(&**name) {
+            Some(renamed) => return Value::Symbol(renamed.as_str().into()),
+            None => return form.clone(),
+        },
+        Value::List(items) => items,
+        _ => return form.clone(),
+    };
+
+    let depth = match head_symbol(items) {
+        Some("quote") if depth == 0 => return form.clone(),
+        Some("quasiquote") => depth + 1,
+        Some("unquote" | "unquote-splicing") if depth > 0 => depth - 1,
+        _ => depth,
+    };
+    Value::List(
+        items
+            .iter()
+            .map(|item| rename(item, renames, depth))
+            .collect(),
+    )
+}
@@ -0,0 +1,675 @@
+//! Bytecode optimisations, run between code generation and the VM.
+//!
+//! Inlining looks at the whole program, to find the functions it defines.
+//! Every other pass works on one function body at a time, deleting or replacing
+//! instructions, after which jumps are moved to keep pointing at the same
+//! code. Passes never change an instruction that is a jump target except by
+//! replacing it with code that has the same effect, so jumps into a
+//! rewritten sequence stay valid. They repeat until none of them finds
+//! anything left to do.
+
+use crate::expander::Expander;
+use crate::intern::SymbolId;
+use crate::resolver::Capture;
+use crate::simulator::generate;
+use crate::simulator::read;
+use crate::simulator::Function;
+use crate::simulator::Instruction;
+use crate::simulator::Register;
+use crate::simulator::Value;
+use crate::simulator::RESULT;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use anyhow::Result;
+
+/// Functions with bodies of at most this many instructions are inlined,
+/// unless [`Options`] says otherwise.
+pub const INLINE_THRESHOLD: usize = 12;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// See [`optimize`]. Level 2 also inlines calls.
+    pub level: u8,
+    /// Largest function body inlined, in instructions.
+    pub inline_threshold: usize,
+}
+
+impl Options {
+    pub fn new(level: u8) -> Self {
+        Self {
+            level,
+            inline_threshold: INLINE_THRESHOLD,
+        }
+    }
+}
+
+/// Optimises a whole program, given as the code of each top-level form in
+/// order. Calls are inlined across forms, and the other passes then run on
+/// each form separately, so the forms keep their boundaries.
+pub fn optimize_program(
+    mut forms: Vec<Vec<Instruction>>,
+    options: &Options,
+) -> Vec<Vec<Instruction>> {
+    if options.level >= 2 {
+        inline_calls(&mut forms, options.inline_threshold);
+    }
+    forms
+        .into_iter()
+        .map(|form| optimize(form, options.level))
+        .collect()
+}
+
+/// Generates the code of each of `forms`, optimised as one program.
+pub fn generate_optimized(forms: &[Value], options: &Options) -> Result<Vec<Vec<Instruction>>> {
+    let forms = forms
+        .iter()
+        .map(|form| generate(std::slice::from_ref(form)))
+        .collect::<Result<_>>()?;
+    Ok(optimize_program(forms, options))
+}
+
+/// Like [`compile`](crate::simulator::compile), optimising the program.
+pub fn compile_optimized(src: &str, options: &Options) -> Result<Vec<Instruction>> {
+    let forms = Expander::new().expand_all(read(src)?)?;
+    Ok(generate_optimized(&forms, options)?.concat())
+}
+
+/// What to optimise in one piece of code:
+///
+/// - 0: nothing.
+/// - 1: fold constant arithmetic, lists and conditions, following constants
+///   through registers, and remove unreachable code, jumps to the next
+///   instruction and writes to registers nothing reads.
+/// - 2: also read registers that were copied from others from the originals
+///   instead, so the copies can go.
+pub fn optimize(bytecode: Vec<Instruction>, level: u8) -> Vec<Instruction> {
+    if level == 0 {
+        return bytecode;
+    }
+
+    let mut code = bytecode
+        .into_iter()
+        .map(|instruction| match instruction {
+            Instruction::DefineFunction(mut function) => {
+                if let Function::User { bytecode, .. } = Rc::make_mut(&mut function) {
+                    *bytecode = optimize(std::mem::take(bytecode), level);
+                }
+                Instruction::DefineFunction(function)
+            }
+            other => other,
+        })
+        .collect::<Vec<_>>();
+
+    loop {
+        let mut changed = fold_constants(&mut code);
+        if level >= 2 {
+            changed |= forward_copies(&mut code);
+        }
+        changed |= remove_dead_writes(&mut code);
+        changed |= remove_unreachable(&mut code);
+        if !changed {
+            return code;
+        }
+    }
+}
+
+/// The register `instruction` writes to, if any.
+fn written(instruction: &Instruction) -> Option<Register> {
+    match instruction {
+        Instruction::Load(dst, _)
+        | Instruction::Move(dst, _)
+        | Instruction::Operation(_, dst, _, _)
+        | Instruction::Call(dst, ..)
+        | Instruction::CallValue(dst, ..)
+        | Instruction::LoadUpvalue(dst, _)
+        | Instruction::LoadGlobal(dst, _)
+        | Instruction::MakeList(dst, ..)
+        | Instruction::ConcatLists(dst, ..) => Some(*dst),
+        Instruction::DefineFunction(_)
+        | Instruction::Jump(_)
+        | Instruction::JumpUnless(..)
+        | Instruction::Return(_) => None,
+    }
+}
+
+/// Calls `f` with each register `instruction` reads, including the ones a
+/// function it defines captures.
+fn for_each_read(instruction: &Instruction, mut f: impl FnMut(Register)) {
+    match instruction {
+        Instruction::Move(_, src) => f(*src),
+        Instruction::Operation(_, _, a, b) => {
+            f(*a);
+            f(*b);
+        }
+        Instruction::Call(_, _, start, n)
+        | Instruction::MakeList(_, start, n)
+        | Instruction::ConcatLists(_, start, n) => registers(*start, *n).for_each(f),
+        Instruction::CallValue(_, callee, start, n) => {
+            f(*callee);
+            registers(*start, *n).for_each(f);
+        }
+        Instruction::JumpUnless(register, _) | Instruction::Return(register) => f(*register),
+        Instruction::DefineFunction(function) => {
+            for capture in function.captures() {
+                if let Capture::Local(register) = capture {
+                    f(*register);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The `n` registers from `start`.
+fn registers(start: Register, n: u16) -> impl Iterator<Item = Register> {
+    (start as usize..start as usize + n as usize).map_while(|r| Register::try_from(r).ok())
+}
+
+/// Where each jump in `code` lands. Includes `code.len()`, for jumps to
+/// the end.
+fn jump_targets(code: &[Instruction]) -> Vec<bool> {
+    let mut targets = vec![false; code.len() + 1];
+    for (i, instruction) in code.iter().enumerate() {
+        if let Some(target) = jump_target(i, instruction) {
+            if let Some(target) = targets.get_mut(target) {
+                *target = true;
+            }
+        }
+    }
+    targets
+}
+
+fn jump_target(i: usize, instruction: &Instruction) -> Option<usize> {
+    match instruction {
+        Instruction::Jump(offset) | Instruction::JumpUnless(_, offset) => Some(i + 1 + offset),
+        _ => None,
+    }
+}
+
+/// Where control can go after instruction `i`.
+fn successors(i: usize, instruction: &Instruction) -> Vec<usize> {
+    match instruction {
+        Instruction::Jump(_) => jump_target(i, instruction).into_iter().collect(),
+        Instruction::JumpUnless(..) => [i + 1]
+            .into_iter()
+            .chain(jump_target(i, instruction))
+            .collect(),
+        Instruction::Return(_) => Vec::new(),
+        _ => vec![i + 1],
+    }
+}
+
+/// Removes the deleted instructions, fixing up jump offsets. A jump to a
+/// deleted instruction goes to the next one that is kept instead.
+fn compact(code: &mut Vec<Instruction>, kept: Vec<Option<Instruction>>) {
+    // `new_index[i]` is where old instruction `i`, or the next kept one, ends up
+    let mut new_index = Vec::with_capacity(kept.len() + 1);
+    let mut count = 0;
+    for instruction in &kept {
+        new_index.push(count);
+        count += usize::from(instruction.is_some());
+    }
+    new_index.push(count);
+
+    *code = kept
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, instruction)| {
+            let instruction = instruction?;
+            let offset = |old: usize| new_index[i + 1 + old] - new_index[i] - 1;
+            Some(match instruction {
+                Instruction::Jump(old) => Instruction::Jump(offset(old)),
+                Instruction::JumpUnless(condition, old) => {
+                    Instruction::JumpUnless(condition, offset(old))
+                }
+                other => other,
+            })
+        })
+        .collect();
+}
+
+/// Evaluates arithmetic and lists on registers known to hold constants,
+/// and conditions on them. Only looks within straight-line code,
+/// forgetting everything at each jump target.
+fn fold_constants(code: &mut Vec<Instruction>) -> bool {
+    let targets = jump_targets(code);
+    let mut known = HashMap::new();
+    let mut kept = Vec::with_capacity(code.len());
+    let mut changed = false;
+
+    for (i, instruction) in code.iter().enumerate() {
+        if targets[i] {
+            known.clear();
+        }
+        let folded = match instruction {
+            Instruction::Move(dst, src) => known
+                .get(src)
+                .map(|value: &Value| Instruction::Load(*dst, value.clone())),
+            Instruction::Operation(op, dst, a, b) => match (known.get(a), known.get(b)) {
+                // Errors are left for the VM to report
+                (Some(a), Some(b)) => op
+                    .binary(a, b)
+                    .ok()
+                    .map(|value| Instruction::Load(*dst, value)),
+                _ => None,
+            },
+            Instruction::MakeList(dst, start, n) => registers(*start, *n)
+                .map(|register| known.get(&register).cloned())
+                .collect::<Option<Vec<_>>>()
+                .filter(|items| items.len() == *n as usize)
+                .map(|items| Instruction::Load(*dst, Value::List(items.into()))),
+            Instruction::JumpUnless(condition, offset) => {
+                if let Some(value) = known.get(condition) {
+                    kept.push((!value.is_truthy()).then_some(Instruction::Jump(*offset)));
+                    changed = true;
+                    continue;
+                }
+                None
+            }
+            _ => None,
+        };
+
+        changed |= folded.is_some();
+        let instruction = folded.unwrap_or_else(|| instruction.clone());
+        match &instruction {
+            Instruction::Load(dst, value) => {
+                known.insert(*dst, value.clone());
+            }
+            other => {
+                if let Some(dst) = written(other) {
+                    known.remove(&dst);
+                }
+            }
+        }
+        kept.push(Some(instruction));
+    }
+
+    if changed {
+        compact(code, kept);
+    }
+    changed
+}
+
+/// Replaces reads of a register that was copied from another with reads of
+/// the original, as long as neither has been written since. Only looks
+/// within straight-line code, forgetting everything at each jump target.
+/// Arguments stay where they are, as they must be consecutive.
+fn forward_copies(code: &mut [Instruction]) -> bool {
+    let targets = jump_targets(code);
+    let mut copies = HashMap::new();
+    let mut changed = false;
+
+    for (i, instruction) in code.iter_mut().enumerate() {
+        if targets[i] {
+            copies.clear();
+        }
+        let mut forward = |register: &mut Register| {
+            if let Some(original) = copies.get(register) {
+                *register = *original;
+                changed = true;
+            }
+        };
+        match instruction {
+            Instruction::Move(_, src) => forward(src),
+            Instruction::Operation(_, _, a, b) => {
+                forward(a);
+                forward(b);
+            }
+            Instruction::CallValue(_, callee, ..) => forward(callee),
+            Instruction::JumpUnless(register, _) | Instruction::Return(register) => {
+                forward(register)
+            }
+            Instruction::DefineFunction(function) => {
+                for capture in Rc::make_mut(function).captures_mut() {
+                    if let Capture::Local(register) = capture {
+                        forward(register);
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        if let Some(dst) = written(instruction) {
+            copies.retain(|copy, original| *copy != dst && *original != dst);
+        }
+        if let Instruction::Move(dst, src) = instruction {
+            if dst != src {
+                copies.insert(*dst, *src);
+            }
+        }
+    }
+    changed
+}
+
+/// The registers that may be read after each instruction, before they are
+/// written again. [`RESULT`] is read once the code ends.
+fn live_after(code: &[Instruction]) -> Vec<HashSet<Register>> {
+    let mut live_before = vec![HashSet::new(); code.len() + 1];
+    live_before[code.len()].insert(RESULT);
+    let mut live_after = vec![HashSet::new(); code.len()];
+
+    // Jumps only go forwards, so going backwards sees every successor first
+    for i in (0..code.len()).rev() {
+        let mut live = HashSet::new();
+        for successor in successors(i, &code[i]) {
+            if let Some(before) = live_before.get(successor) {
+                live.extend(before);
+            }
+        }
+        live_after[i] = live.clone();
+        if let Some(dst) = written(&code[i]) {
+            live.remove(&dst);
+        }
+        for_each_read(&code[i], |register| {
+            live.insert(register);
+        });
+        live_before[i] = live;
+    }
+    live_after
+}
+
+/// Removes instructions that only write a register nothing reads
+/// afterwards, and copies of a register to itself. Instructions that may
+/// fail or have side effects stay.
+fn remove_dead_writes(code: &mut Vec<Instruction>) -> bool {
+    let live = live_after(code);
+    let mut changed = false;
+    let kept = code
+        .iter()
+        .zip(live)
+        .map(|(instruction, live)| {
+            let dead = match instruction {
+                Instruction::Move(dst, src) if dst == src => true,
+                Instruction::Load(dst, _)
+                | Instruction::Move(dst, _)
+                | Instruction::LoadUpvalue(dst, _)
+                | Instruction::MakeList(dst, ..) => !live.contains(dst),
+                _ => false,
+            };
+            changed |= dead;
+            (!dead).then(|| instruction.clone())
+        })
+        .collect::<Vec<_>>();
+
+    if changed {
+        compact(code, kept);
+    }
+    changed
+}
+
+/// Removes instructions no path from the start reaches, and jumps that
+/// skip nothing.
+fn remove_unreachable(code: &mut Vec<Instruction>) -> bool {
+    let mut reachable = vec![false; code.len()];
+    let mut pending = vec![0];
+    while let Some(i) = pending.pop() {
+        if i >= code.len() || reachable[i] {
+            continue;
+        }
+        reachable[i] = true;
+        pending.extend(successors(i, &code[i]));
+    }
+
+    let mut changed = false;
+    let kept = code
+        .iter()
+        .zip(reachable)
+        .map(|(instruction, reachable)| match (instruction, reachable) {
+            (Instruction::Jump(0), _) | (_, false) => {
+                changed = true;
+                None
+            }
+            _ => Some(instruction.clone()),
+        })
+        .collect::<Vec<_>>();
+
+    if changed {
+        compact(code, kept);
+    }
+    changed
+}
+
+/// A function that calls can be replaced with the body of.
+struct Inlinable {
+    args: usize,
+    /// The body up to its `Return`, which is its last instruction.
+    bytecode: Vec<Instruction>,
+    /// The register it returns.
+    result: Register,
+}
+
+/// Replaces calls to small functions with their bodies, working on the
+/// argument registers of the call and on fresh registers of the caller.
+///
+/// A function is only inlined if it is defined once, at the top level of a
+/// form where no jump skips the definition, and doesn't capture anything,
+/// define functions or call itself. Only calls in later forms are inlined,
+/// which can't run before the definition. Forms are handled in order, so
+/// the bodies inlined have had calls inlined into them already, and
+/// mutually recursive functions are only unrolled once.
+fn inline_calls(forms: &mut [Vec<Instruction>], threshold: usize) {
+    let mut definitions = HashMap::new();
+    for form in forms.iter() {
+        count_definitions(form, &mut definitions);
+    }
+
+    let mut inlinable = HashMap::new();
+    for form in forms.iter_mut() {
+        if !inlinable.is_empty() {
+            inline_in(form, 0, &inlinable);
+        }
+
+        for (i, instruction) in form.iter().enumerate() {
+            let Instruction::DefineFunction(function) = instruction else {
+                continue;
+            };
+            let Function::User {
+                name,
+                args,
+                captures,
+                bytecode,
+                ..
+            } = &**function
+            else {
+                continue;
+            };
+            let Some((Instruction::Return(result), body)) = bytecode.split_last() else {
+                continue;
+            };
+            let id = SymbolId::intern(name);
+            let simple = body.iter().all(|instruction| match instruction {
+                Instruction::Call(_, callee, ..) => *callee != id,
+                Instruction::DefineFunction(_)
+                | Instruction::LoadUpvalue(..)
+                | Instruction::Return(_) => false,
+                _ => true,
+            });
+            let skipped = form[..i]
+                .iter()
+                .enumerate()
+                .any(|(j, jump)| jump_target(j, jump).is_some_and(|target| target > i));
+            if definitions[&id] == 1
+                && captures.is_empty()
+                && bytecode.len() <= threshold
+                && simple
+                && !skipped
+            {
+                let function = Inlinable {
+                    args: args.len(),
+                    bytecode: body.to_vec(),
+                    result: *result,
+                };
+                inlinable.insert(id, function);
+            }
+        }
+    }
+}
+
+fn count_definitions(code: &[Instruction], definitions: &mut HashMap<SymbolId, usize>) {
+    for instruction in code {
+        if let Instruction::DefineFunction(function) = instruction {
+            *definitions
+                .entry(SymbolId::intern(function.name()))
+                .or_default() += 1;
+            if let Function::User { bytecode, .. } = &**function {
+                count_definitions(bytecode, definitions);
+            }
+        }
+    }
+}
+
+/// Inlines calls in `code`, and in the functions it defines. `params` is
+/// the number of parameters `code` takes, if it is a function body.
+fn inline_in(code: &mut Vec<Instruction>, params: usize, inlinable: &HashMap<SymbolId, Inlinable>) {
+    for instruction in code.iter_mut() {
+        if let Instruction::DefineFunction(function) = instruction {
+            if let Function::User { args, bytecode, .. } = Rc::make_mut(function) {
+                inline_in(bytecode, args.len(), inlinable);
+            }
+        }
+    }
+
+    // Inlined bodies work in registers after every one the code uses
+    let mut base = params.max(RESULT as usize + 1);
+    for instruction in code.iter() {
+        let mut count = |register: Register| base = base.max(register as usize + 1);
+        written(instruction).map(&mut count);
+        for_each_read(instruction, count);
+    }
+
+    let mut changed = false;
+    let expansions = code
+        .iter()
+        .map(|instruction| {
+            let Instruction::Call(dst, name, start, argc) = instruction else {
+                return None;
+            };
+            let function = inlinable.get(name)?;
+            if function.args != *argc as usize {
+                return None;
+            }
+            let expansion = inline_body(function, *dst, *start, base)?;
+            changed = true;
+            Some(expansion)
+        })
+        .collect::<Vec<_>>();
+
+    if changed {
+        expand(code, expansions);
+    }
+}
+
+/// The body of `function`, with its parameters in the argument registers
+/// from `start`, its other registers from `base` on, and its result copied
+/// into `dst`. `None` if the registers don't fit.
+fn inline_body(
+    function: &Inlinable,
+    dst: Register,
+    start: Register,
+    base: usize,
+) -> Option<Vec<Instruction>> {
+    let rename = |register: Register| {
+        let register = register as usize;
+        let renamed = match register < function.args {
+            true => start as usize + register,
+            false => base + register - function.args,
+        };
+        Register::try_from(renamed).ok()
+    };
+
+    let mut code = Vec::with_capacity(function.bytecode.len() + 1);
+    for instruction in &function.bytecode {
+        let mut instruction = instruction.clone();
+        rename_registers(&mut instruction, &rename)?;
+        code.push(instruction);
+    }
+    code.push(Instruction::Move(dst, rename(function.result)?));
+    Some(code)
+}
+
+/// Renames every register `instruction` uses. `None` if `rename` fails,
+/// or splits up registers that must be consecutive.
+fn rename_registers(
+    instruction: &mut Instruction,
+    rename: &impl Fn(Register) -> Option<Register>,
+) -> Option<()> {
+    let consecutive = |start: &mut Register, n: u16| {
+        if n > 0 {
+            let renamed = rename(*start)?;
+            for k in 1..n {
+                if rename(start.checked_add(k)?)? != renamed.checked_add(k)? {
+                    return None;
+                }
+            }
+            *start = renamed;
+        }
+        Some(())
+    };
+
+    match instruction {
+        Instruction::Load(register, _)
+        | Instruction::LoadUpvalue(register, _)
+        | Instruction::LoadGlobal(register, _)
+        | Instruction::JumpUnless(register, _)
+        | Instruction::Return(register) => *register = rename(*register)?,
+        Instruction::Move(dst, src) => {
+            *dst = rename(*dst)?;
+            *src = rename(*src)?;
+        }
+        Instruction::Operation(_, dst, a, b) => {
+            *dst = rename(*dst)?;
+            *a = rename(*a)?;
+            *b = rename(*b)?;
+        }
+        Instruction::Call(dst, _, start, n)
+        | Instruction::MakeList(dst, start, n)
+        | Instruction::ConcatLists(dst, start, n) => {
+            *dst = rename(*dst)?;
+            consecutive(start, *n)?;
+        }
+        Instruction::CallValue(dst, callee, start, n) => {
+            *dst = rename(*dst)?;
+            *callee = rename(*callee)?;
+            consecutive(start, *n)?;
+        }
+        Instruction::DefineFunction(function) => {
+            for capture in Rc::make_mut(function).captures_mut() {
+                if let Capture::Local(register) = capture {
+                    *register = rename(*register)?;
+                }
+            }
+        }
+        Instruction::Jump(_) => {}
+    }
+    Some(())
+}
+
+/// Replaces the instructions that have an expansion with it. Jumps between
+/// the original instructions keep pointing at the same code; those within
+/// an expansion are relative to it already.
+fn expand(code: &mut Vec<Instruction>, expansions: Vec<Option<Vec<Instruction>>>) {
+    let mut new_index = Vec::with_capacity(code.len() + 1);
+    let mut count = 0;
+    for expansion in &expansions {
+        new_index.push(count);
+        count += expansion.as_ref().map_or(1, Vec::len);
+    }
+    new_index.push(count);
+
+    let mut expanded = Vec::with_capacity(count);
+    for (i, (instruction, expansion)) in code.drain(..).zip(expansions).enumerate() {
+        let offset = |old: usize| new_index[i + 1 + old] - new_index[i] - 1;
+        match (instruction, expansion) {
+            (_, Some(expansion)) => expanded.extend(expansion),
+            (Instruction::Jump(old), None) => expanded.push(Instruction::Jump(offset(old))),
+            (Instruction::JumpUnless(condition, old), None) => {
+                expanded.push(Instruction::JumpUnless(condition, offset(old)))
+            }
+            (other, None) => expanded.push(other),
+        }
+    }
+    *code = expanded;
+}
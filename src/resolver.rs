@@ -0,0 +1,144 @@
+use crate::intern::SymbolId;
+use crate::simulator::Register;
+use crate::simulator::RESULT;
+use crate::Error;
+
+use anyhow::Result;
+
+/// Where a function gets the value of one of its upvalues from when it is
+/// defined, in the frame of the function around it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capture {
+    Local(Register),
+    Upvalue(u16),
+}
+
+/// What an identifier refers to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolved {
+    /// A parameter or `let` binding of the current function, by register.
+    Local(Register),
+    /// A local of an enclosing function, copied into the current one when
+    /// it was defined.
+    Upvalue(u16),
+    /// Anything else is looked up by name when it is used.
+    Global(SymbolId),
+}
+
+#[derive(Default)]
+struct FunctionScope {
+    /// Register `i` holds the local `registers[i]`, or a temporary if it is
+    /// `None`; later bindings shadow earlier ones.
+    registers: Vec<Option<SymbolId>>,
+    upvalues: Vec<(SymbolId, Capture)>,
+}
+
+/// Resolves identifiers to registers while code is generated, tracking the
+/// bindings in scope and the registers in use. The outermost function is
+/// the top level, whose `let` bindings are locals too.
+///
+/// Registers are allocated like a stack: [`Scope::mark`] before allocating,
+/// and [`Scope::release`] everything allocated since when done.
+pub(crate) struct Scope {
+    functions: Vec<FunctionScope>,
+}
+
+impl Scope {
+    pub fn new() -> Self {
+        // The top level leaves its value in `RESULT`
+        let top_level = FunctionScope {
+            registers: vec![None; RESULT as usize + 1],
+            upvalues: Vec::new(),
+        };
+        Self {
+            functions: vec![top_level],
+        }
+    }
+
+    /// Binds `name` in a new register.
+    pub fn push_local(&mut self, name: SymbolId) -> Result<Register> {
+        self.push(Some(name))
+    }
+
+    /// A new register for an intermediate value.
+    pub fn push_temp(&mut self) -> Result<Register> {
+        self.push(None)
+    }
+
+    fn push(&mut self, name: Option<SymbolId>) -> Result<Register> {
+        let registers = &mut self.current().registers;
+        let register =
+            Register::try_from(registers.len()).map_err(|_| Error::Expected("fewer locals"))?;
+        registers.push(name);
+        Ok(register)
+    }
+
+    /// Binds `name` to a register from [`Scope::push_temp`], once it holds
+    /// the value.
+    pub fn bind(&mut self, register: Register, name: SymbolId) {
+        self.current().registers[register as usize] = Some(name);
+    }
+
+    /// The registers in use, to [`Scope::release`] the ones allocated after.
+    pub fn mark(&mut self) -> usize {
+        self.current().registers.len()
+    }
+
+    pub fn release(&mut self, mark: usize) {
+        self.current().registers.truncate(mark);
+    }
+
+    /// Starts the body of a function taking `params`, which become its first
+    /// registers.
+    pub fn enter_function(&mut self, params: &[SymbolId]) -> Result<()> {
+        self.functions.push(FunctionScope::default());
+        for param in params {
+            self.push_local(*param)?;
+        }
+        Ok(())
+    }
+
+    /// Ends the body of the current function, returning where to capture each
+    /// of its upvalues from.
+    pub fn leave_function(&mut self) -> Vec<Capture> {
+        let function = self.functions.pop().expect("entered a function");
+        function
+            .upvalues
+            .into_iter()
+            .map(|(_, capture)| capture)
+            .collect()
+    }
+
+    pub fn resolve(&mut self, name: SymbolId) -> Result<Resolved> {
+        self.resolve_in(self.functions.len() - 1, name)
+    }
+
+    fn resolve_in(&mut self, function: usize, name: SymbolId) -> Result<Resolved> {
+        let scope = &self.functions[function];
+        if let Some(register) = scope.registers.iter().rposition(|r| *r == Some(name)) {
+            return Ok(Resolved::Local(register as Register));
+        }
+        if let Some(index) = scope.upvalues.iter().position(|(n, _)| *n == name) {
+            return Ok(Resolved::Upvalue(index as u16));
+        }
+        if function == 0 {
+            return Ok(Resolved::Global(name));
+        }
+
+        let capture = match self.resolve_in(function - 1, name)? {
+            Resolved::Local(register) => Capture::Local(register),
+            Resolved::Upvalue(index) => Capture::Upvalue(index),
+            global => return Ok(global),
+        };
+        let upvalues = &mut self.functions[function].upvalues;
+        let index = u16::try_from(upvalues.len()).map_err(|_| Error::Expected("fewer upvalues"))?;
+        upvalues.push((name, capture));
+        Ok(Resolved::Upvalue(index))
+    }
+
+    fn current(&mut self) -> &mut FunctionScope {
+        self.functions
+            .last_mut()
+            .expect("the top level is never left")
+    }
+}
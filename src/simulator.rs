@@ -1,32 +1,153 @@
+use crate::ast::Cst;
 use crate::ast::Tree;
+use crate::builtins;
+use crate::expander::Expander;
+use crate::gc::Heap;
+use crate::gc::HeapStats;
+use crate::intern::SymbolId;
+use crate::lexer::lex;
+use crate::lexer::normalize_ident;
+use crate::lexer::OwnedToken;
+use crate::lexer::StreamLexer;
 use crate::lexer::Symbol;
+use crate::lexer::Token;
+use crate::resolver::Capture;
+use crate::resolver::Resolved;
+use crate::resolver::Scope;
 use crate::Error;
 
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::io::BufRead;
+use std::rc::Rc;
+use std::sync::atomic;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
 
 use anyhow::Result;
 
+/// Lithos source embedded into every [`Vm`] at startup.
+const PRELUDE: &str = include_str!("prelude.li");
+
+/// Symbols made by [`Vm::gensym`] so far, over every VM.
+static GENSYM_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
 #[derive(Debug, Clone)]
 pub enum Ast<'a> {
     NumberLiteral(i32),
     StringLiteral(String),
-    Identifier(String),
+    Identifier(SymbolId),
     Call { name: &'a str, args: Vec<Ast<'a>> },
+    Quote(Value),
+    Quasiquote(Template<'a>),
+}
+
+/// A quasiquoted expression, with the parts to evaluate picked out.
+#[derive(Debug, Clone)]
+pub enum Template<'a> {
+    Quoted(Value),
+    Unquoted(Box<Ast<'a>>),
+    Spliced(Box<Ast<'a>>),
+    List(Vec<Template<'a>>),
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, Clone)]
 pub enum Value {
+    Nil,
+    Bool(bool),
     Signed32(i32),
+    String(Rc<str>),
+    Symbol(Rc<str>),
+    List(Rc<[Value]>),
+    Function(Rc<Function>),
+}
+
+impl Value {
+    /// The value of `(quote tree)`.
+    pub fn from_tree(tree: &Tree) -> Self {
+        match tree {
+            Tree::Leaf(Symbol::Number(n)) => Value::Signed32(*n),
+            Tree::Leaf(Symbol::StringLiteral(s)) => Value::String((*s).into()),
+            Tree::Leaf(Symbol::Ident(ident)) => Value::Symbol(normalize_ident(ident).into()),
+            Tree::Branch(children) => Value::List(children.iter().map(Value::from_tree).collect()),
+        }
+    }
+
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    /// `nil` doubles as the empty list, like in most Lisps.
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::Nil => Some(&[]),
+            Value::List(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn compare(&self, other: &Value) -> Result<Ordering> {
+        match (self, other) {
+            (Value::Signed32(a), Value::Signed32(b)) => Ok(a.cmp(b)),
+            _ => Err(Error::Expected("two numbers to compare").into()),
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Signed32(a), Value::Signed32(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Symbol(a), Value::Symbol(b)) => a == b,
+            (Value::List(a), Value::List(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
 }
 
 impl std::fmt::Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(b) => write!(f, "{b}"),
             Value::Signed32(n) => write!(f, "{}", *n),
+            Value::String(s) => write!(f, "{s}"),
+            Value::Symbol(s) => write!(f, "{s}"),
+            Value::List(items) => {
+                write!(f, "(")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    match item {
+                        // Nested strings keep their quotes so the list reads back the same
+                        Value::String(s) => write!(f, "{s:?}")?,
+                        _ => write!(f, "{item}")?,
+                    }
+                }
+                write!(f, ")")
+            }
+            Value::Function(func) => write!(f, "<function {}>", func.name()),
         }
     }
 }
 
+/// Arithmetic on 32-bit integers, wrapping around on overflow. As in
+/// Scheme, each operation takes any number of operands:
+///
+/// - `(- a b c)` is `(a - b) - c`, and likewise for the others.
+/// - `(op x)` is `(op e x)`, where `e` is 0 for `+` and `-` and 1 for `*`
+///   and `/`. So `(- x)` negates `x` and `(/ x)` is its reciprocal.
+/// - `(+)` is 0 and `(*)` is 1, while `-` and `/` need an operand.
+///
+/// Division truncates towards zero, so `(/ 7 2)` is 3 and `(/ 2)` is 0,
+/// and dividing by zero is an error.
 #[derive(Debug, Clone)]
 pub enum Op {
     Add,
@@ -35,79 +156,133 @@ pub enum Op {
     Div,
 }
 
-pub type Stack = Vec<Value>;
-
 impl Op {
-    pub fn eval(&self, arg_count: usize, stack: &mut Stack) -> Result<()> {
-        let values = (1..=arg_count)
-            .map(|i| {
-                stack
-                    .pop()
-                    .ok_or(Error::UnexpectedArgN(arg_count, i).into())
-            })
-            .collect::<Result<Vec<_>>>()?
-            .into_iter()
-            .map(|value| match value {
-                Value::Signed32(n) => Ok(n),
-            })
-            .collect::<Result<Vec<_>>>()?;
-
-        let op = match self {
-            Op::Add => i32::wrapping_add,
-            Op::Sub => i32::wrapping_sub,
-            Op::Mul => i32::wrapping_mul,
-            Op::Div => i32::wrapping_div,
-        };
-
-        let result = values
-            .into_iter()
-            .reduce(op)
-            .ok_or(Error::Expected(".reduce() not to fail"))?;
+    /// The operation on any number of operands, as the builtin of the same
+    /// name does it. Operands are taken in turn, so the first bad one is
+    /// reported, like when the operation is compiled.
+    pub fn apply(&self, values: Vec<Value>) -> Result<Value> {
+        match &values[..] {
+            [] => match self {
+                Op::Add | Op::Mul => Ok(Value::Signed32(self.identity())),
+                Op::Sub | Op::Div => Err(Error::Expected("at least one operand").into()),
+            },
+            [x] => self.binary(&Value::Signed32(self.identity()), x),
+            [first, rest @ ..] => rest
+                .iter()
+                .try_fold(first.clone(), |acc, x| self.binary(&acc, x)),
+        }
+    }
 
-        stack.push(Value::Signed32(result));
+    /// The operation on two values, as [`Instruction::Operation`] does it.
+    pub fn binary(&self, a: &Value, b: &Value) -> Result<Value> {
+        let (Value::Signed32(a), Value::Signed32(b)) = (a, b) else {
+            return Err(Error::Expected("number").into());
+        };
+        let result = match self {
+            Op::Add => a.wrapping_add(*b),
+            Op::Sub => a.wrapping_sub(*b),
+            Op::Mul => a.wrapping_mul(*b),
+            Op::Div if *b == 0 => return Err(Error::DivisionByZero.into()),
+            Op::Div => a.wrapping_div(*b),
+        };
+        Ok(Value::Signed32(result))
+    }
 
-        Ok(())
+    /// The `e` that `(op x)` is `(op e x)` with.
+    pub fn identity(&self) -> i32 {
+        match self {
+            Op::Add | Op::Sub => 0,
+            Op::Mul | Op::Div => 1,
+        }
     }
 }
 
+/// Native function. Receives its arguments in call order and may call back
+/// into the interpreter through the [`Vm`].
+pub type Builtin = fn(&mut Vm, Vec<Value>) -> Result<Value>;
+
+/// Like [`Builtin`], but able to capture state. Used for functions built at
+/// runtime by other natives, such as the result of `compose`.
+pub type NativeClosure = Rc<dyn Fn(&mut Vm, Vec<Value>) -> Result<Value>>;
+
 #[derive(Clone)]
 pub enum Function {
     Builtin {
         name: String,
-        inner: fn(&mut Stack) -> Result<()>,
+        inner: Builtin,
+    },
+    Native {
+        name: String,
+        inner: NativeClosure,
     },
     User {
         name: String,
-        args: Vec<String>,
+        args: Vec<SymbolId>,
+        /// Where [`Instruction::DefineFunction`] gets each upvalue from.
+        captures: Vec<Capture>,
+        /// The captured values, once defined. Lithos code can't tie these
+        /// into cycles, but a host replacing them could, which [`Vm::gc`]
+        /// frees.
+        upvalues: RefCell<Rc<[Value]>>,
         bytecode: Vec<Instruction>,
     },
 }
 
 impl Function {
-    pub fn eval(
-        &self,
-        stack: &mut Stack,
-        variables: &mut Variables,
-        functions: &mut Functions,
-    ) -> Result<()> {
+    pub fn call(&self, vm: &mut Vm, values: Vec<Value>) -> Result<Value> {
         match self {
-            Function::Builtin { inner, .. } => inner(stack),
-            Function::User { args, bytecode, .. } => {
-                // FIXME
-                let prev_state = variables.clone();
-                for (i, arg) in args.into_iter().rev().enumerate() {
-                    let value = stack.pop().ok_or(Error::UnexpectedArgN(args.len(), i))?;
-                    variables.insert(arg.to_string(), value);
-                }
-                interpert(&bytecode, stack, variables, functions)?;
-                *variables = prev_state;
-                Ok(())
+            Function::Builtin { inner, .. } => inner(vm, values),
+            Function::Native { inner, .. } => inner(vm, values),
+            Function::User { .. } => {
+                let frame = vm.registers.len();
+                vm.check_registers(frame + values.len())?;
+                vm.registers.extend(values);
+                vm.call_user(self, frame)
             }
         }
     }
-    fn name(&self) -> &str {
+
+    /// Replaces the upvalue at `index` of a user function, returning the old
+    /// value, or `None` if there is no such upvalue. Only for tests of the
+    /// collector, as it ties closures into cycles programs can't make.
+    #[cfg(test)]
+    pub(crate) fn set_upvalue(&self, index: usize, value: Value) -> Option<Value> {
+        let Function::User { upvalues, .. } = self else {
+            return None;
+        };
+        let mut values = upvalues.borrow().to_vec();
+        let old = std::mem::replace(values.get_mut(index)?, value);
+        upvalues.replace(values.into());
+        Some(old)
+    }
+
+    /// The code of a user function, or nothing for natives.
+    pub(crate) fn bytecode(&self) -> &[Instruction] {
+        match self {
+            Function::User { bytecode, .. } => bytecode,
+            _ => &[],
+        }
+    }
+
+    /// Where a user function gets its upvalues from, or nothing for natives.
+    pub(crate) fn captures(&self) -> &[Capture] {
+        match self {
+            Function::User { captures, .. } => captures,
+            _ => &[],
+        }
+    }
+
+    pub(crate) fn captures_mut(&mut self) -> &mut [Capture] {
+        match self {
+            Function::User { captures, .. } => captures,
+            _ => &mut [],
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
         match self {
             Function::Builtin { name, .. } => name,
+            Function::Native { name, .. } => name,
             Function::User { name, .. } => name,
         }
     }
@@ -117,44 +292,66 @@ impl std::fmt::Debug for Function {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Function::Builtin { name, .. } => write!(f, "Builtin function '{name}'"),
+            Function::Native { name, .. } => write!(f, "Native function '{name}'"),
             Function::User { name, .. } => write!(f, "User-defined function '{name}'"),
         }
     }
 }
 
+/// A slot of the running function's frame. Parameters come first, then
+/// `let` bindings and intermediate values.
+pub type Register = u16;
+
+/// Where top-level code leaves its value, and what code that runs off its
+/// end returns.
+pub const RESULT: Register = 0;
+
+/// The first operand of an instruction that produces a value is the
+/// register it goes into. Arguments are passed in consecutive registers,
+/// given as the first one and a count.
 #[derive(Debug, Clone)]
 pub enum Instruction {
-    Load(Value),
-    Operation(Op, usize),
-    Call(String),
-    ReadVar(String),
-    StoreVar(String),
-    ForgetVar(String),
-    DefineFunction(Function),
-}
-
-impl Instruction {
-    fn store_var(&self) -> Option<&str> {
-        match self {
-            Self::StoreVar(s) => Some(s),
-            _ => None,
-        }
-    }
+    Load(Register, Value),
+    /// Copy the second register into the first.
+    Move(Register, Register),
+    /// Apply the operation to the last two registers.
+    Operation(Op, Register, Register, Register),
+    /// Call the global function with this name.
+    Call(Register, SymbolId, Register, u16),
+    /// Call the function in the second register.
+    CallValue(Register, Register, Register, u16),
+    LoadUpvalue(Register, u16),
+    /// Load a global function as a value.
+    LoadGlobal(Register, SymbolId),
+    /// Define a global function, capturing its upvalues from the current frame.
+    /// Shared, as code read from a file may define the same function in many
+    /// places.
+    DefineFunction(Rc<Function>),
+    /// Skip the next `n` instructions.
+    Jump(usize),
+    /// Skip the next `n` instructions if the register is falsy.
+    JumpUnless(Register, usize),
+    /// Make a list of the registers.
+    MakeList(Register, Register, u16),
+    /// Concatenate the lists in the registers.
+    ConcatLists(Register, Register, u16),
+    /// End the function, returning the register.
+    Return(Register),
 }
 
-fn builtin_echo(stack: &mut Stack) -> Result<()> {
-    let a = stack.pop().ok_or(Error::UnexpectedArgN(1, 0))?;
-    println!("{}", a);
-    Ok(())
+pub(crate) fn create_user_function(args: &[Ast]) -> Result<Function> {
+    define_function(args, &mut Scope::new())
 }
 
-fn create_user_function(args: &[Ast]) -> Result<Instruction> {
-    let mut args = args.into_iter();
+/// `(fn name (params...) body)`, nested in the functions of `scope`.
+fn define_function(args: &[Ast], scope: &mut Scope) -> Result<Function> {
+    let mut args = args.iter();
     let name = args
         .next()
         .ok_or(Error::UnexpectedArgN(3, 0))?
         .ident()
         .ok_or(Error::Expected("identifier"))?
+        .name()
         .to_string();
 
     let fn_args = args
@@ -163,200 +360,1154 @@ fn create_user_function(args: &[Ast]) -> Result<Instruction> {
         .call_list()
         .ok_or(Error::Expected("all arguments to be identifiers"))?;
 
-    let body = args.next().ok_or(Error::UnexpectedArgN(3, 2))?.generate()?;
+    let body = args.next().ok_or(Error::UnexpectedArgN(3, 2))?;
+    scope.enter_function(&fn_args)?;
+    let mut bytecode = Vec::new();
+    let result = body.operand(scope, &mut bytecode);
+    let captures = scope.leave_function();
+    bytecode.push(Instruction::Return(result?));
 
-    Ok(Instruction::DefineFunction(Function::User {
+    Ok(Function::User {
         name: name.clone(),
         args: fn_args,
-        bytecode: body,
-    }))
-}
-
-fn match_call<'a>(name: &'a str, args: &'a [Ast]) -> Result<Instruction> {
-    Ok(match name {
-        "+" => Instruction::Operation(Op::Add, args.len()),
-        "-" => Instruction::Operation(Op::Sub, args.len()),
-        "*" => Instruction::Operation(Op::Mul, args.len()),
-        "/" => Instruction::Operation(Op::Div, args.len()),
-        "let" => Instruction::StoreVar(match &args[0] {
-            Ast::Identifier(ident) => ident.to_string(),
-            _ => return Err(Error::Expected("identifier").into()),
-        }),
-        "fn" => create_user_function(args)?,
-        _ => Instruction::Call(name.to_string()),
+        captures,
+        upvalues: RefCell::default(),
+        bytecode,
     })
 }
 
+fn arg_count(args: &[Ast]) -> Result<u16> {
+    u16::try_from(args.len()).map_err(|_| Error::Expected("fewer arguments").into())
+}
+
+/// Evaluates `args` into consecutive new registers, returning the first.
+/// They are evaluated last first, the order of the stack machine this VM
+/// replaced.
+fn push_args(args: &[Ast], scope: &mut Scope, code: &mut Vec<Instruction>) -> Result<Register> {
+    let registers = args
+        .iter()
+        .map(|_| scope.push_temp())
+        .collect::<Result<Vec<_>>>()?;
+    for (arg, register) in args.iter().zip(&registers).rev() {
+        arg.generate_into(*register, scope, code)?;
+    }
+    // With no arguments, any register will do
+    Ok(registers.first().copied().unwrap_or(RESULT))
+}
+
 fn push_let_in(
-    instruction: Instruction,
     args: &[Ast],
-    instructions: &mut Vec<Instruction>,
+    dst: Register,
+    scope: &mut Scope,
+    code: &mut Vec<Instruction>,
+) -> Result<()> {
+    let [name, value, body] = args else {
+        return Err(Error::UnexpectedArgN(3, args.len()).into());
+    };
+    let name = name.ident().ok_or(Error::Expected("identifier"))?;
+
+    let mark = scope.mark();
+    let register = scope.push_temp()?;
+    value.generate_into(register, scope, code)?;
+    // Only in scope once it has its value
+    scope.bind(register, name);
+    body.generate_into(dst, scope, code)?;
+    scope.release(mark);
+    Ok(())
+}
+
+/// Chains the operation over its operands, which are evaluated last first.
+fn push_operation(
+    op: Op,
+    args: &[Ast],
+    dst: Register,
+    scope: &mut Scope,
+    code: &mut Vec<Instruction>,
 ) -> Result<()> {
-    let name = instruction.store_var().unwrap().to_string();
-    instructions.extend(args[1].generate()?); // Push variable value
-    instructions.push(instruction); // Push Store
-    instructions.extend(args[2].generate()?); // Push Expression
-    instructions.push(Instruction::ForgetVar(name)); // Forget the variable
+    let mark = scope.mark();
+    let mut operands = vec![RESULT; args.len()];
+    for (arg, operand) in args.iter().zip(&mut operands).rev() {
+        *operand = arg.operand(scope, code)?;
+    }
+    let mut lhs = operands[0];
+    for rhs in &operands[1..] {
+        code.push(Instruction::Operation(op.clone(), dst, lhs, *rhs));
+        lhs = dst;
+    }
+    scope.release(mark);
     Ok(())
 }
 
-fn push_normal_instruction(
-    instruction: Instruction,
+fn push_if(
     args: &[Ast],
-    instructions: &mut Vec<Instruction>,
+    dst: Register,
+    scope: &mut Scope,
+    code: &mut Vec<Instruction>,
 ) -> Result<()> {
-    for arg in args.into_iter().rev() {
-        let bytecode = arg.generate()?;
-        instructions.extend(bytecode);
+    let (condition, then, otherwise) = match args {
+        [condition, then] => (condition, then, None),
+        [condition, then, otherwise] => (condition, then, Some(otherwise)),
+        _ => return Err(Error::UnexpectedArgN(3, args.len()).into()),
+    };
+
+    let mark = scope.mark();
+    let condition = condition.operand(scope, code)?;
+    scope.release(mark);
+
+    let mut then_code = Vec::new();
+    then.generate_into(dst, scope, &mut then_code)?;
+    let mut otherwise_code = Vec::new();
+    match otherwise {
+        Some(otherwise) => otherwise.generate_into(dst, scope, &mut otherwise_code)?,
+        None => otherwise_code.push(Instruction::Load(dst, Value::Nil)),
     }
-    instructions.push(instruction);
+
+    // Skip `then` and its jump
+    code.push(Instruction::JumpUnless(condition, then_code.len() + 1));
+    code.extend(then_code);
+    code.push(Instruction::Jump(otherwise_code.len()));
+    code.extend(otherwise_code);
     Ok(())
 }
 
-fn make_call(name: &str, args: &[Ast], instructions: &mut Vec<Instruction>) -> Result<()> {
-    let instruction = match_call(name, args)?;
-    match instruction {
-        Instruction::StoreVar(_) => push_let_in(instruction, args, instructions)?,
-        Instruction::DefineFunction(_) => instructions.push(instruction),
-        _ => push_normal_instruction(instruction, args, instructions)?,
+/// Part of a list template with splices in it.
+enum Segment<'t, 'a> {
+    /// A run of ordinary items, which becomes a list of its own.
+    Items(Vec<&'t Template<'a>>),
+    Spliced(&'t Ast<'a>),
+}
+
+fn push_template(
+    template: &Template,
+    dst: Register,
+    scope: &mut Scope,
+    code: &mut Vec<Instruction>,
+) -> Result<()> {
+    let items = match template {
+        Template::Quoted(value) => {
+            code.push(Instruction::Load(dst, value.clone()));
+            return Ok(());
+        }
+        Template::Unquoted(ast) => return ast.generate_into(dst, scope, code),
+        Template::Spliced(_) => {
+            return Err(Error::Expected("unquote-splicing inside a list").into())
+        }
+        Template::List(items) => items,
+    };
+
+    if !items
+        .iter()
+        .any(|item| matches!(item, Template::Spliced(_)))
+    {
+        let items = items.iter().collect::<Vec<_>>();
+        return push_list(&items, dst, scope, code);
     }
 
+    // Runs of ordinary items become lists of their own, then everything is concatenated
+    let mut segments = Vec::new();
+    for item in items {
+        match (item, segments.last_mut()) {
+            (Template::Spliced(ast), _) => segments.push(Segment::Spliced(ast)),
+            (_, Some(Segment::Items(run))) => run.push(item),
+            _ => segments.push(Segment::Items(vec![item])),
+        }
+    }
+
+    let mark = scope.mark();
+    let registers = segments
+        .iter()
+        .map(|_| scope.push_temp())
+        .collect::<Result<Vec<_>>>()?;
+    for (segment, register) in segments.iter().zip(&registers) {
+        match segment {
+            Segment::Items(run) => push_list(run, *register, scope, code)?,
+            Segment::Spliced(ast) => ast.generate_into(*register, scope, code)?,
+        }
+    }
+    code.push(Instruction::ConcatLists(
+        dst,
+        registers[0],
+        registers.len() as u16,
+    ));
+    scope.release(mark);
+    Ok(())
+}
+
+/// Makes a list of `items`, evaluated in order.
+fn push_list(
+    items: &[&Template],
+    dst: Register,
+    scope: &mut Scope,
+    code: &mut Vec<Instruction>,
+) -> Result<()> {
+    let mark = scope.mark();
+    let registers = items
+        .iter()
+        .map(|_| scope.push_temp())
+        .collect::<Result<Vec<_>>>()?;
+    for (item, register) in items.iter().zip(&registers) {
+        push_template(item, *register, scope, code)?;
+    }
+    let start = registers.first().copied().unwrap_or(RESULT);
+    code.push(Instruction::MakeList(dst, start, registers.len() as u16));
+    scope.release(mark);
+    Ok(())
+}
+
+fn make_call(
+    name: &str,
+    args: &[Ast],
+    dst: Register,
+    scope: &mut Scope,
+    code: &mut Vec<Instruction>,
+) -> Result<()> {
+    let op = match name {
+        "if" => return push_if(args, dst, scope, code),
+        "let" => return push_let_in(args, dst, scope, code),
+        "fn" => {
+            code.push(Instruction::DefineFunction(Rc::new(define_function(
+                args, scope,
+            )?)));
+            return Ok(());
+        }
+        "+" => Some(Op::Add),
+        "-" => Some(Op::Sub),
+        "*" => Some(Op::Mul),
+        "/" => Some(Op::Div),
+        _ => None,
+    };
+    match (op, args) {
+        (Some(op), [_, _, ..]) => return push_operation(op, args, dst, scope, code),
+        (Some(op), [arg]) => {
+            let args = [Ast::NumberLiteral(op.identity()), arg.clone()];
+            return push_operation(op, &args, dst, scope, code);
+        }
+        (Some(op @ (Op::Add | Op::Mul)), []) => {
+            code.push(Instruction::Load(dst, Value::Signed32(op.identity())));
+            return Ok(());
+        }
+        // The builtin of the same name reports the missing operand
+        (Some(_), []) => {
+            let name = SymbolId::intern(name);
+            code.push(Instruction::Call(dst, name, RESULT, 0));
+            return Ok(());
+        }
+        (None, _) => {}
+    }
+
+    let mark = scope.mark();
+    let callee = SymbolId::intern(name);
+    let resolved = scope.resolve(callee)?;
+    let start = push_args(args, scope, code)?;
+    let argc = arg_count(args)?;
+    match resolved {
+        Resolved::Global(name) => code.push(Instruction::Call(dst, name, start, argc)),
+        // A variable holding a function
+        _ => {
+            let callee = Ast::Identifier(callee).operand(scope, code)?;
+            code.push(Instruction::CallValue(dst, callee, start, argc));
+        }
+    }
+    scope.release(mark);
     Ok(())
 }
 
 impl<'a> Ast<'a> {
-    pub fn from_tree(tree: &'a Tree) -> Result<Self> {
-        match tree {
-            Tree::Branch(_) => ast_from_branch(tree),
-            Tree::Leaf(_) => ast_from_leaf(tree),
+    /// Code is data: lists become calls, symbols identifiers. Values with no
+    /// literal syntax, which only macros can produce, evaluate to themselves.
+    pub fn from_datum(datum: &'a Value) -> Result<Self> {
+        match datum {
+            // `()` evaluates to itself, which also gives `(fn f () ...)` its parameter list
+            Value::List(items) if items.is_empty() => Ok(Ast::Quote(datum.clone())),
+            Value::List(items) => ast_from_list(items),
+            Value::Signed32(n) => Ok(Ast::NumberLiteral(*n)),
+            Value::String(s) => Ok(Ast::StringLiteral(s.to_string())),
+            Value::Symbol(ident) => Ok(Ast::Identifier(SymbolId::intern(ident))),
+            other => Ok(Ast::Quote(other.clone())),
         }
     }
 
+    /// Generates top-level code, which leaves its value in [`RESULT`].
     pub fn generate(&self) -> Result<Vec<Instruction>> {
-        let mut instructions = Vec::new();
+        let mut code = Vec::new();
+        self.generate_into(RESULT, &mut Scope::new(), &mut code)?;
+        Ok(code)
+    }
+
+    /// Generates code that puts the value of `self` in `dst`. Only `fn`,
+    /// which has no value, leaves `dst` alone.
+    fn generate_into(
+        &self,
+        dst: Register,
+        scope: &mut Scope,
+        code: &mut Vec<Instruction>,
+    ) -> Result<()> {
         match self {
-            Ast::NumberLiteral(n) => instructions.push(Instruction::Load(Value::Signed32(*n))),
-            Ast::Identifier(ident) => instructions.push(Instruction::ReadVar(ident.clone())),
-            Ast::Call { name, args } => make_call(name, args, &mut instructions)?,
-            _ => return Err(Error::Unimplemented("ast variant").into()),
+            Ast::NumberLiteral(n) => code.push(Instruction::Load(dst, Value::Signed32(*n))),
+            Ast::Identifier(ident) => code.push(match &*ident.name() {
+                "nil" => Instruction::Load(dst, Value::Nil),
+                "true" => Instruction::Load(dst, Value::Bool(true)),
+                "false" => Instruction::Load(dst, Value::Bool(false)),
+                _ => match scope.resolve(*ident)? {
+                    Resolved::Local(register) => Instruction::Move(dst, register),
+                    Resolved::Upvalue(index) => Instruction::LoadUpvalue(dst, index),
+                    Resolved::Global(name) => Instruction::LoadGlobal(dst, name),
+                },
+            }),
+            Ast::StringLiteral(s) => {
+                code.push(Instruction::Load(dst, Value::String(s.as_str().into())))
+            }
+            Ast::Call { name, args } => make_call(name, args, dst, scope, code)?,
+            Ast::Quote(value) => code.push(Instruction::Load(dst, value.clone())),
+            Ast::Quasiquote(template) => push_template(template, dst, scope, code)?,
+        }
+        Ok(())
+    }
+
+    /// A register holding the value of `self`: the local's own, if it is
+    /// one, or else a new one from `scope`.
+    fn operand(&self, scope: &mut Scope, code: &mut Vec<Instruction>) -> Result<Register> {
+        if let Ast::Identifier(ident) = self {
+            if !matches!(&*ident.name(), "nil" | "true" | "false") {
+                if let Resolved::Local(register) = scope.resolve(*ident)? {
+                    return Ok(register);
+                }
+            }
         }
-        Ok(instructions)
+        let register = scope.push_temp()?;
+        self.generate_into(register, scope, code)?;
+        Ok(register)
     }
 
-    fn ident(&self) -> Option<&str> {
+    fn ident(&self) -> Option<SymbolId> {
         match self {
-            Self::Identifier(ident) => Some(ident),
+            Self::Identifier(ident) => Some(*ident),
             _ => None,
         }
     }
 
-    fn call_list(&self) -> Option<Vec<String>> {
+    fn call_list(&self) -> Option<Vec<SymbolId>> {
         match self {
             Self::Call { name, args } => {
-                let mut accum = vec![name.to_string()];
+                let mut accum = vec![SymbolId::intern(name)];
                 for arg in args {
                     match arg {
-                        Self::Identifier(ident) => accum.push(ident.to_string()),
+                        Self::Identifier(ident) => accum.push(*ident),
                         _ => return None,
                     }
                 }
                 Some(accum)
             }
-            Self::Identifier(ident) => Some(vec![ident.to_string()]),
+            Self::Identifier(ident) => Some(vec![*ident]),
+            Self::Quote(Value::List(items)) if items.is_empty() => Some(Vec::new()),
             _ => None,
         }
     }
 }
 
-fn ast_from_leaf<'a>(tree: &'a Tree) -> Result<Ast<'a>> {
-    match tree {
-        Tree::Leaf(leaf) => match leaf {
-            Symbol::Number(n) => Ok(Ast::NumberLiteral(*n)),
-            Symbol::StringLiteral(s) => Ok(Ast::StringLiteral(s.to_string())),
-            Symbol::Ident(ident) => Ok(Ast::Identifier(ident.to_string())),
-        },
-        _ => Err(Error::Expected("Tree::Leaf").into()),
+impl<'a> Template<'a> {
+    /// `depth` counts the enclosing quasiquotes, only unquotes at depth 1 are evaluated.
+    fn from_datum(datum: &'a Value, depth: usize) -> Result<Self> {
+        let items = match datum {
+            Value::List(items) => items,
+            _ => return Ok(Template::Quoted(datum.clone())),
+        };
+
+        let template = match (head_symbol(items), &items[..]) {
+            (Some("unquote"), [_, expr]) if depth == 1 => {
+                Template::Unquoted(Box::new(Ast::from_datum(expr)?))
+            }
+            (Some("unquote-splicing"), [_, expr]) if depth == 1 => {
+                Template::Spliced(Box::new(Ast::from_datum(expr)?))
+            }
+            (Some(name @ ("unquote" | "unquote-splicing")), [_, expr]) => {
+                let inner = Template::from_datum(expr, depth - 1)?;
+                Template::List(vec![Template::Quoted(Value::Symbol(name.into())), inner])
+            }
+            (Some("quasiquote"), [_, expr]) => {
+                let inner = Template::from_datum(expr, depth + 1)?;
+                Template::List(vec![
+                    Template::Quoted(Value::Symbol("quasiquote".into())),
+                    inner,
+                ])
+            }
+            _ => Template::List(
+                items
+                    .iter()
+                    .map(|item| Template::from_datum(item, depth))
+                    .collect::<Result<_>>()?,
+            ),
+        };
+
+        Ok(template.simplify())
+    }
+
+    /// Folds lists with nothing to evaluate back into a constant.
+    fn simplify(self) -> Self {
+        match self {
+            Template::List(items) if items.iter().all(|i| matches!(i, Template::Quoted(_))) => {
+                let values = items.into_iter().map(|item| match item {
+                    Template::Quoted(value) => value,
+                    _ => unreachable!(),
+                });
+                Template::Quoted(Value::List(values.collect()))
+            }
+            other => other,
+        }
     }
 }
 
-fn ast_from_branch<'a>(tree: &'a Tree) -> Result<Ast<'a>> {
-    let branch = tree.branch().ok_or(Error::Expected("Tree::Branch"))?;
+/// The name of the symbol a form starts with, e.g. `fn` for `(fn square n ...)`.
+pub(crate) fn head_symbol(items: &[Value]) -> Option<&str> {
+    match items.first() {
+        Some(Value::Symbol(name)) => Some(name),
+        _ => None,
+    }
+}
 
-    let name = match branch
-        .first()
-        .ok_or(Error::Expected("nonempty branch"))?
-        .leaf()
-        .ok_or(Error::Expected("Tree::Leaf"))?
-    {
-        Symbol::Ident(s) => *s,
+fn ast_from_list(items: &[Value]) -> Result<Ast<'_>> {
+    let name = match items.first().ok_or(Error::Expected("nonempty list"))? {
+        Value::Symbol(name) => &**name,
         _ => return Err(Error::Expected("Symbol::Ident").into()),
     };
 
-    let args = match branch.get(1..) {
-        Some(rst) => rst.into_iter().map(Ast::from_tree).collect(),
-        None => Ok(Vec::new()),
-    }?;
+    match (name, items) {
+        ("quote", [_, datum]) => return Ok(Ast::Quote(datum.clone())),
+        ("quasiquote", [_, datum]) => return Ok(Ast::Quasiquote(Template::from_datum(datum, 1)?)),
+        ("quote" | "quasiquote", _) => return Err(Error::UnexpectedArgN(1, items.len() - 1).into()),
+        ("unquote" | "unquote-splicing", _) => {
+            return Err(Error::Expected("unquote inside quasiquote").into())
+        }
+        ("import" | "require", _) => {
+            return Err(Error::Expected("import at the top level of a file").into())
+        }
+        _ => {}
+    }
+
+    let args = items[1..]
+        .iter()
+        .map(Ast::from_datum)
+        .collect::<Result<_>>()?;
 
     Ok(Ast::Call { name, args })
 }
 
-fn register_builtin(functions: &mut Functions, name: &str, f: fn(&mut Stack) -> Result<()>) {
-    functions.insert(
-        name.to_string(),
-        Function::Builtin {
-            name: name.to_string(),
-            inner: f,
-        },
-    );
+/// Values by [`SymbolId`], in a vector indexed by the ID.
+struct Slots<T>(Vec<Option<T>>);
+
+impl<T> Slots<T> {
+    fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    fn get(&self, id: SymbolId) -> Option<&T> {
+        self.0.get(id.index())?.as_ref()
+    }
+
+    /// Sets or clears the slot of `id`, returning what was in it.
+    fn replace(&mut self, id: SymbolId, value: Option<T>) -> Option<T> {
+        if id.index() >= self.0.len() {
+            self.0.resize_with(id.index() + 1, || None);
+        }
+        std::mem::replace(&mut self.0[id.index()], value)
+    }
 }
 
-type Functions = HashMap<String, Function>;
-type Variables = HashMap<String, Value>;
+/// Shared, so calling a function only copies a pointer, and a function
+/// that redefines itself keeps running the code it started with.
+type Functions = Slots<Rc<Function>>;
 
-fn interpert(
-    bytecode: &[Instruction],
-    stack: &mut Stack,
-    variables: &mut Variables,
-    functions: &mut Functions,
-) -> Result<()> {
-    for instruction in bytecode {
+/// How deep user calls may nest, unless [`Limits`] says otherwise. Calls
+/// take memory rather than Rust stack, so this only catches runaway
+/// recursion before it takes all of it.
+pub const DEFAULT_CALL_DEPTH: usize = 100_000;
+
+/// Natives calling back into the VM, like `map` calling a function, that
+/// may be in progress at once. Each nests on the Rust stack.
+pub const MAX_NESTED_RUNS: usize = 128;
+
+/// Caps on what a [`Vm`] may use, so code that can't be trusted to stop
+/// fails with an error instead of hanging or exhausting memory. `None` is
+/// unlimited, and by default only the call depth is capped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Instructions each evaluation may execute, counting those of the
+    /// functions it calls.
+    pub instructions: Option<u64>,
+    /// User function calls in progress at once.
+    pub call_depth: Option<usize>,
+    /// Registers of all active frames together.
+    pub registers: Option<usize>,
+    /// Live heap bytes, as counted by [`HeapStats::live_bytes`].
+    pub heap_bytes: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            instructions: None,
+            call_depth: Some(DEFAULT_CALL_DEPTH),
+            registers: None,
+            heap_bytes: None,
+        }
+    }
+}
+
+/// Instructions run between checks for cancellation, and for running out
+/// of fuel.
+const CHECK_INTERVAL: u64 = 1024;
+
+/// Stops a running [`Vm`] from another thread or a signal handler: once
+/// cancelled, the evaluation fails with [`Error::Interrupted`] within a few
+/// instructions. Clones share one flag, which each evaluation clears when
+/// it starts, so cancelling while nothing runs has no effect.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, atomic::Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(atomic::Ordering::Relaxed)
+    }
+
+    /// The flag behind the token, for APIs that set one, like
+    /// `signal_hook::flag::register`.
+    pub fn flag(&self) -> &Arc<AtomicBool> {
+        &self.0
+    }
+
+    fn reset(&self) {
+        self.0.store(false, atomic::Ordering::Relaxed);
+    }
+}
+
+pub struct Vm {
+    /// Registers of every active frame, the current one starting at `frame`.
+    /// A frame grows as its registers are written, and registers past its
+    /// end read as nil.
+    registers: Vec<Value>,
+    frame: usize,
+    /// Captured values of the running function.
+    upvalues: Rc<[Value]>,
+    functions: Functions,
+    heap: Heap,
+    limits: Limits,
+    /// Instructions left in the running evaluation.
+    fuel: u64,
+    /// User function calls in progress.
+    depth: usize,
+    /// Runs of [`Vm::interpert`] in progress, each deeper on the Rust stack.
+    nested_runs: usize,
+    /// Whether an evaluation is running, so the ones it starts, like the
+    /// calls made by `map`, share its fuel.
+    running: bool,
+    cancel: CancelToken,
+}
+
+impl Vm {
+    /// Creates a VM with all builtins registered and the prelude loaded.
+    pub fn new() -> Result<Self> {
+        let mut vm = Self {
+            registers: Vec::new(),
+            frame: 0,
+            upvalues: Rc::new([]),
+            functions: Functions::new(),
+            heap: Heap::new(),
+            limits: Limits::default(),
+            fuel: u64::MAX,
+            depth: 0,
+            nested_runs: 0,
+            running: false,
+            cancel: CancelToken::default(),
+        };
+        builtins::register_all(&mut vm);
+        vm.run(&compile(PRELUDE)?)?;
+        Ok(vm)
+    }
+
+    pub fn register_builtin(&mut self, name: &str, f: Builtin) {
+        self.functions.replace(
+            SymbolId::intern(name),
+            Some(Rc::new(Function::Builtin {
+                name: name.to_string(),
+                inner: f,
+            })),
+        );
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Applies `limits` from the next evaluation on.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// A token that stops whatever this VM is running.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    pub fn run(&mut self, bytecode: &[Instruction]) -> Result<()> {
+        self.eval_bytecode(bytecode).map(drop)
+    }
+
+    /// Compiles and runs `src`, returning the value of its last expression.
+    pub fn eval(&mut self, src: &str) -> Result<Value> {
+        self.eval_bytecode(&compile(src)?)
+    }
+
+    /// Runs `bytecode`, returning the value of its last expression.
+    pub fn eval_bytecode(&mut self, bytecode: &[Instruction]) -> Result<Value> {
+        self.evaluate(|vm| {
+            // Top-level code gets a frame of its own for its `let` bindings
+            let frame = std::mem::replace(&mut vm.frame, vm.registers.len());
+            let result = vm.interpert(bytecode);
+            vm.registers.truncate(vm.frame);
+            vm.frame = frame;
+            result
+        })
+    }
+
+    /// Calls a function value with `args` and returns its result.
+    pub fn call(&mut self, f: &Value, args: Vec<Value>) -> Result<Value> {
+        match f {
+            Value::Function(func) => self.evaluate(|vm| func.call(vm, args)),
+            other => Err(Error::NotCallable(other.to_string()).into()),
+        }
+    }
+
+    /// Runs `f` with a full tank of fuel, unless it is part of an evaluation
+    /// that is already running.
+    fn evaluate<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        if self.running {
+            return f(self);
+        }
+        self.fuel = self.limits.instructions.unwrap_or(u64::MAX);
+        self.cancel.reset();
+        self.running = true;
+        let result = f(self);
+        self.running = false;
+        result
+    }
+
+    /// A list value, tracked by the collector. Natives that build lists
+    /// should make them through this, so cycles through them can be freed.
+    pub fn alloc_list(&mut self, items: impl Into<Rc<[Value]>>) -> Result<Value> {
+        let list = items.into();
+        self.heap.track_list(&list);
+        self.check_heap(0)?;
+        Ok(Value::List(list))
+    }
+
+    /// Like [`Vm::alloc_list`], for functions.
+    pub fn alloc_function(&mut self, func: Function) -> Result<Rc<Function>> {
+        let func = Rc::new(func);
+        self.heap.track_function(&func);
+        self.check_heap(0)?;
+        Ok(func)
+    }
+
+    /// Frees the cycles of lists and functions that nothing else refers to,
+    /// returning how many objects were in them. This also runs on its own
+    /// as the heap grows.
+    pub fn gc(&mut self) -> usize {
+        self.heap.collect()
+    }
+
+    pub fn heap_stats(&self) -> HeapStats {
+        self.heap.stats()
+    }
+
+    /// Fails if a list of `len` values would take the heap over its limit,
+    /// for natives to check before building one.
+    pub(crate) fn make_room(&mut self, len: usize) -> Result<()> {
+        self.check_heap(len.saturating_mul(std::mem::size_of::<Value>()))
+    }
+
+    /// Collects if it is time to, or if the heap, with `extra` bytes more,
+    /// has gone over its limit, which it may only seem to have until freed
+    /// objects are noticed.
+    fn check_heap(&mut self, extra: usize) -> Result<()> {
+        let over_limit = |vm: &Self| {
+            vm.limits
+                .heap_bytes
+                .filter(|max| vm.heap.stats().live_bytes.saturating_add(extra) > *max)
+        };
+        if self.heap.wants_collection() || over_limit(self).is_some() {
+            self.gc();
+        }
+        match over_limit(self) {
+            Some(max) => Err(Error::HeapLimit(max).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Fails if the running evaluation is out of fuel or has been cancelled.
+    fn check_budget(&self) -> Result<()> {
+        if self.fuel == 0 {
+            let max = self.limits.instructions.unwrap_or(u64::MAX);
+            return Err(Error::InstructionLimit(max).into());
+        }
+        if self.cancel.is_cancelled() {
+            return Err(Error::Interrupted.into());
+        }
+        Ok(())
+    }
+
+    /// Fails if the registers of all frames can't grow to `len`.
+    fn check_registers(&self, len: usize) -> Result<()> {
+        match self.limits.registers {
+            Some(max) if len > max => Err(Error::RegisterLimit(max).into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// A fresh symbol name, for macros that need temporaries of their own.
+    /// The `|` in it can't appear in a name the lexer reads, so no code can
+    /// refer to it by accident, and the count is shared by all VMs, so the
+    /// macros of different modules don't hand out the same names.
+    pub fn gensym(&mut self, prefix: &str) -> String {
+        let n = GENSYM_COUNTER.fetch_add(1, atomic::Ordering::Relaxed) + 1;
+        format!("#:{prefix}|{n}")
+    }
+
+    fn lookup_function(&self, name: SymbolId) -> Result<Rc<Function>> {
+        self.functions
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::UnknownFunction(name.to_string()).into())
+    }
+
+    fn read_global(&self, name: SymbolId) -> Result<Value> {
+        self.functions
+            .get(name)
+            .map(|func| Value::Function(Rc::clone(func)))
+            .ok_or_else(|| Error::UnknownVariable(name.to_string()).into())
+    }
+
+    fn get(&self, register: Register) -> &Value {
+        const NIL: &Value = &Value::Nil;
+        self.registers
+            .get(self.frame + register as usize)
+            .unwrap_or(NIL)
+    }
+
+    fn set(&mut self, register: Register, value: Value) -> Result<()> {
+        let index = self.frame + register as usize;
+        if index >= self.registers.len() {
+            self.check_registers(index + 1)?;
+            self.registers.resize(index + 1, Value::Nil);
+        }
+        self.registers[index] = value;
+        Ok(())
+    }
+
+    /// `len` registers from `start`.
+    fn slice(&mut self, start: Register, len: u16) -> Result<&[Value]> {
+        let start = self.frame + start as usize;
+        let end = start + len as usize;
+        if end > self.registers.len() {
+            self.check_registers(end)?;
+            self.registers.resize(end, Value::Nil);
+        }
+        Ok(&self.registers[start..end])
+    }
+
+    /// Copies `argc` registers from `start` to the end, where they become
+    /// the first registers of a new frame, and returns where it starts.
+    fn push_args(&mut self, start: Register, argc: u16) -> Result<usize> {
+        // Reading them may grow the caller's frame, so that goes first
+        self.slice(start, argc)?;
+        let frame = self.registers.len();
+        self.check_registers(frame + argc as usize)?;
+        let start = self.frame + start as usize;
+        self.registers
+            .extend_from_within(start..start + argc as usize);
+        Ok(frame)
+    }
+
+    /// Makes the registers from `frame` to the end, which hold the arguments
+    /// of `func`, the current frame. Returns the caller's frame and upvalues,
+    /// for [`Vm::leave`].
+    fn enter(&mut self, func: &Function, frame: usize) -> Result<(usize, Rc<[Value]>)> {
+        let Function::User { args, upvalues, .. } = func else {
+            unreachable!("only user functions have frames");
+        };
+        if args.len() != self.registers.len() - frame {
+            let given = self.registers.len() - frame;
+            self.registers.truncate(frame);
+            return Err(Error::UnexpectedArgN(args.len(), given).into());
+        }
+        if let Some(max) = self.limits.call_depth.filter(|max| self.depth >= *max) {
+            self.registers.truncate(frame);
+            return Err(Error::CallDepthLimit(max).into());
+        }
+
+        self.depth += 1;
+        let caller_frame = std::mem::replace(&mut self.frame, frame);
+        let caller_upvalues = std::mem::replace(&mut self.upvalues, upvalues.borrow().clone());
+        Ok((caller_frame, caller_upvalues))
+    }
+
+    /// Drops the current frame and goes back to the caller's.
+    fn leave(&mut self, frame: usize, upvalues: Rc<[Value]>) {
+        self.registers.truncate(self.frame);
+        self.frame = frame;
+        self.upvalues = upvalues;
+        self.depth -= 1;
+    }
+
+    /// Runs a user function, whose arguments are the registers from `frame`
+    /// to the end.
+    fn call_user(&mut self, func: &Function, frame: usize) -> Result<Value> {
+        let (caller_frame, caller_upvalues) = self.enter(func, frame)?;
+        let result = self.interpert(func.bytecode());
+        self.leave(caller_frame, caller_upvalues);
+        result
+    }
+
+    /// `func` with its upvalues copied from the running frame.
+    fn close_over(&self, func: &Function) -> Result<Function> {
+        let mut func = func.clone();
+        if let Function::User {
+            captures, upvalues, ..
+        } = &mut func
+        {
+            *upvalues.get_mut() = captures
+                .iter()
+                .map(|capture| match capture {
+                    Capture::Local(register) => Ok(self.get(*register).clone()),
+                    Capture::Upvalue(index) => self.upvalue(*index),
+                })
+                .collect::<Result<_>>()?;
+        }
+        Ok(func)
+    }
+
+    /// Checked, as bytecode written by hand or read from a file may ask for
+    /// upvalues the running function doesn't have.
+    fn upvalue(&self, index: u16) -> Result<Value> {
+        self.upvalues
+            .get(index as usize)
+            .cloned()
+            .ok_or_else(|| Error::InvalidBytecode("upvalue index out of range").into())
+    }
+
+    /// Runs `bytecode` in the current frame. Code that runs off its end
+    /// returns [`RESULT`].
+    ///
+    /// Calls between user functions are made here, on a stack of callers,
+    /// rather than on the Rust stack, so recursion is only as limited as
+    /// [`Limits::call_depth`]. Natives that call back into the VM, like
+    /// `map`, start another `interpert`, and only [`MAX_NESTED_RUNS`] of
+    /// those may be in progress at once.
+    fn interpert(&mut self, bytecode: &[Instruction]) -> Result<Value> {
+        if self.nested_runs >= MAX_NESTED_RUNS {
+            return Err(Error::NestedRunLimit(MAX_NESTED_RUNS).into());
+        }
+        self.nested_runs += 1;
+        let mut callers = Vec::new();
+        let result = self.run_calls(bytecode, &mut callers);
+        // Left over if a call failed
+        while let Some(caller) = callers.pop() {
+            self.leave(caller.frame, caller.upvalues);
+        }
+        self.nested_runs -= 1;
+        result
+    }
+
+    fn run_calls(&mut self, bytecode: &[Instruction], callers: &mut Vec<Caller>) -> Result<Value> {
+        // The running function, or `None` for `bytecode` itself
+        let mut function: Option<Rc<Function>> = None;
+        let mut pc = 0;
+        loop {
+            let code = function.as_deref().map_or(bytecode, Function::bytecode);
+            let returned = match code.get(pc) {
+                None => self.get(RESULT).clone(),
+                Some(Instruction::Return(register)) => {
+                    self.tick()?;
+                    self.get(*register).clone()
+                }
+                Some(instruction) => {
+                    pc += 1;
+                    self.tick()?;
+                    let (dst, callee, start, argc) = match instruction {
+                        Instruction::Call(dst, name, start, argc) => {
+                            (*dst, self.lookup_function(*name)?, *start, *argc)
+                        }
+                        Instruction::CallValue(dst, callee, start, argc) => {
+                            let callee = match self.get(*callee) {
+                                Value::Function(f) => Rc::clone(f),
+                                other => return Err(Error::NotCallable(other.to_string()).into()),
+                            };
+                            (*dst, callee, *start, *argc)
+                        }
+                        _ => {
+                            self.execute(instruction, &mut pc)?;
+                            continue;
+                        }
+                    };
+
+                    if !matches!(*callee, Function::User { .. }) {
+                        let args = self.slice(start, argc)?.to_vec();
+                        let value = callee.call(self, args)?;
+                        self.set(dst, value)?;
+                        continue;
+                    }
+                    let frame = self.push_args(start, argc)?;
+                    let (frame, upvalues) = self.enter(&callee, frame)?;
+                    callers.push(Caller {
+                        function: function.replace(callee),
+                        pc,
+                        frame,
+                        upvalues,
+                        dst,
+                    });
+                    pc = 0;
+                    continue;
+                }
+            };
+
+            let Some(caller) = callers.pop() else {
+                return Ok(returned);
+            };
+            self.leave(caller.frame, caller.upvalues);
+            function = caller.function;
+            pc = caller.pc;
+            self.set(caller.dst, returned)?;
+        }
+    }
+
+    /// Counts an instruction against the fuel, checking every so often
+    /// whether it has run out or the evaluation has been cancelled.
+    fn tick(&mut self) -> Result<()> {
+        if self.fuel.is_multiple_of(CHECK_INTERVAL) {
+            self.check_budget()?;
+        }
+        self.fuel -= 1;
+        Ok(())
+    }
+
+    /// Runs an instruction that neither calls nor returns.
+    fn execute(&mut self, instruction: &Instruction, pc: &mut usize) -> Result<()> {
         match instruction {
-            Instruction::Load(value) => stack.push(value.clone()),
-            Instruction::Operation(op, arg_count) => op.eval(*arg_count, stack)?,
-            Instruction::Call(func_name) => {
-                let f = functions
-                    .get(func_name)
-                    .cloned() // AAGGH this defeats the whole point
-                    .ok_or(Error::UnknownFunction(func_name.to_string()))?;
-                f.eval(stack, variables, functions)?;
+            Instruction::Load(dst, value) => self.set(*dst, value.clone())?,
+            Instruction::Move(dst, src) => self.set(*dst, self.get(*src).clone())?,
+            Instruction::Operation(op, dst, a, b) => {
+                let value = op.binary(self.get(*a), self.get(*b))?;
+                self.set(*dst, value)?;
             }
-            Instruction::ReadVar(name) => {
-                stack.push(variables.get(name).expect("Unknown variable").clone())
+            Instruction::LoadUpvalue(dst, index) => self.set(*dst, self.upvalue(*index)?)?,
+            Instruction::LoadGlobal(dst, name) => {
+                let value = self.read_global(*name)?;
+                self.set(*dst, value)?;
             }
-            Instruction::StoreVar(name) => {
-                variables.insert(
-                    name.to_string(),
-                    stack.pop().ok_or(Error::Expected("nonempty stack"))?,
-                );
+            Instruction::DefineFunction(func) => {
+                let name = SymbolId::intern(func.name());
+                let func = self.close_over(func)?;
+                let func = self.alloc_function(func)?;
+                self.functions.replace(name, Some(func));
             }
-            Instruction::ForgetVar(name) => {
-                variables.remove(name);
+            Instruction::Jump(offset) => *pc += offset,
+            Instruction::JumpUnless(condition, offset) => {
+                if !self.get(*condition).is_truthy() {
+                    *pc += offset;
+                }
             }
-            Instruction::DefineFunction(func) => {
-                functions.insert(func.name().to_string(), func.clone());
+            Instruction::MakeList(dst, start, len) => {
+                let items = Rc::<[Value]>::from(self.slice(*start, *len)?);
+                let list = self.alloc_list(items)?;
+                self.set(*dst, list)?;
+            }
+            Instruction::ConcatLists(dst, start, len) => {
+                let mut items = Vec::new();
+                for list in self.slice(*start, *len)? {
+                    let list = list.as_list().ok_or(Error::Expected("list to splice"))?;
+                    items.extend(list.iter().cloned());
+                }
+                let list = self.alloc_list(items)?;
+                self.set(*dst, list)?;
+            }
+            Instruction::Call(..) | Instruction::CallValue(..) | Instruction::Return(_) => {
+                unreachable!("calls and returns are run by `run_calls`")
             }
         }
+        Ok(())
     }
-    Ok(())
 }
 
-pub fn run(bytecode: Vec<Instruction>) -> Result<()> {
-    let mut stack = Stack::new();
+/// A call in progress in [`Vm::interpert`], to go back to once its callee
+/// returns.
+struct Caller {
+    /// `None` for the code `interpert` was started with.
+    function: Option<Rc<Function>>,
+    pc: usize,
+    frame: usize,
+    upvalues: Rc<[Value]>,
+    /// The register of the caller's the result goes into.
+    dst: Register,
+}
 
-    let mut variables = Variables::new();
-    let mut functions = Functions::new();
+impl Drop for Vm {
+    /// Frees the cycles only the VM could reach, which would otherwise leak.
+    fn drop(&mut self) {
+        self.registers.clear();
+        self.functions = Functions::new();
+        self.upvalues = Rc::new([]);
+        self.gc();
+    }
+}
+
+/// Lexes and parses `src` into one datum per top-level form.
+pub fn read(src: &str) -> Result<Vec<Value>> {
+    read_tokens(&lex(src)?)
+}
 
-    register_builtin(&mut functions, "echo", builtin_echo);
+/// Like [`read`], pairing each form with the 1-based line it starts on.
+pub fn read_with_lines(src: &str) -> Result<Vec<(Value, usize)>> {
+    let forms = read(src)?;
+    let mut offset = 0;
+    let mut lines = Vec::new();
+    for node in Cst::parse(src)? {
+        if !node.is_trivia() {
+            lines.push(src[..offset].matches('\n').count() + 1);
+        }
+        offset += node.to_string().len();
+    }
+    Ok(forms.into_iter().zip(lines).collect())
+}
+
+pub fn read_tokens(tokens: &[Token]) -> Result<Vec<Value>> {
+    let tree = Tree::try_construct(tokens)?;
+    let forms = tree.branch().ok_or(Error::Expected("Tree::Branch"))?;
+    Ok(forms.iter().map(Value::from_tree).collect())
+}
+
+/// Reads top-level forms from `reader` one at a time, each as soon as its
+/// last token has been read, so a REPL can evaluate them as they are typed.
+pub struct FormReader<R> {
+    tokens: StreamLexer<R>,
+    /// Tokens of the form being read.
+    pending: Vec<OwnedToken>,
+    depth: usize,
+    /// Top-level prefixes waiting for their expression, `true` for `#;`.
+    prefixes: Vec<bool>,
+    /// Forms read but not yet returned.
+    ready: VecDeque<Value>,
+}
+
+impl<R: BufRead> FormReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            tokens: StreamLexer::new(reader),
+            pending: Vec::new(),
+            depth: 0,
+            prefixes: Vec::new(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    fn read_pending(&mut self) -> Result<()> {
+        let tokens = self
+            .pending
+            .iter()
+            .map(OwnedToken::as_token)
+            .collect::<Vec<_>>();
+        let forms = read_tokens(&tokens);
+        self.pending.clear();
+        self.depth = 0;
+        self.prefixes.clear();
+        self.ready.extend(forms?);
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Iterator for FormReader<R> {
+    type Item = Result<Value>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.ready.is_empty() {
+            let tok = match self.tokens.next() {
+                Some(Ok((tok, _))) => tok,
+                Some(Err(err)) => {
+                    self.pending.clear();
+                    self.depth = 0;
+                    self.prefixes.clear();
+                    return Some(Err(err));
+                }
+                None if self.pending.is_empty() => return None,
+                // Reports what is left unfinished
+                None => match self.read_pending() {
+                    Ok(()) => continue,
+                    Err(err) => return Some(Err(err)),
+                },
+            };
 
-    interpert(&bytecode, &mut stack, &mut variables, &mut functions)
+            let expression_done = match tok {
+                OwnedToken::Open => {
+                    self.depth += 1;
+                    false
+                }
+                OwnedToken::Close => {
+                    self.depth = self.depth.saturating_sub(1);
+                    self.depth == 0
+                }
+                OwnedToken::Symbol(_) => self.depth == 0,
+                OwnedToken::DatumComment if self.depth == 0 => {
+                    self.prefixes.push(true);
+                    false
+                }
+                OwnedToken::Quote
+                | OwnedToken::Quasiquote
+                | OwnedToken::Unquote
+                | OwnedToken::UnquoteSplicing
+                    if self.depth == 0 =>
+                {
+                    self.prefixes.push(false);
+                    false
+                }
+                _ => false,
+            };
+            self.pending.push(tok);
+
+            // Quotes take the finished expression in, until a `#;` drops it
+            if expression_done {
+                while let Some(skip) = self.prefixes.pop() {
+                    if skip {
+                        break;
+                    }
+                }
+            }
+            if expression_done && self.prefixes.is_empty() {
+                if let Err(err) = self.read_pending() {
+                    return Some(Err(err));
+                }
+            }
+        }
+        self.ready.pop_front().map(Ok)
+    }
+}
+
+/// Generates bytecode for fully macro-expanded top-level forms.
+pub fn generate(forms: &[Value]) -> Result<Vec<Instruction>> {
+    let mut bytecode = Vec::new();
+    for form in forms {
+        bytecode.extend(Ast::from_datum(form)?.generate()?);
+    }
+    Ok(bytecode)
+}
+
+/// Runs the whole read -> expand -> AST -> bytecode pipeline over `src`.
+pub fn compile(src: &str) -> Result<Vec<Instruction>> {
+    let forms = Expander::new().expand_all(read(src)?)?;
+    generate(&forms)
+}
+
+pub fn run(bytecode: Vec<Instruction>) -> Result<()> {
+    Vm::new()?.run(&bytecode)
 }